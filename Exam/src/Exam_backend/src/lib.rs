@@ -1,3 +1,6 @@
+//the package is called Exam_backend to match dfx.json, so the crate name is not snake case
+#![allow(non_snake_case)]
+
 //the code can convert Rust data structures to and from the Candid format, which is necessary for interacting with the IC's canisters.
 //CandidType: A trait that enables a Rust type to be serialized and deserialized using the Candid format, which is an IDL (Interface Definition Language) used in the Internet Computer ecosystem.
 ///Decode: A function or trait for deserializing data from the Candid format.
@@ -14,17 +17,37 @@ use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemor
 //DefaultMemoryImpl: A default implementation of the memory interface.
 //StableBTreeMap: A B-tree map data structure optimized for stable memory storage.
 //Storable: A trait for types that can be stored in stable memory.
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
 
 //Cow: Stands for "Copy on Write." It's a smart pointer that allows for efficient borrowing or cloning of data depending on whether it needs to be modified.
 //RefCell: A type that provides interior mutability, allowing you to mutate data even when the RefCell itself is immutable. It enforces borrow rules at runtime.
+use candid::Principal;
+use ic_cdk_timers::TimerId;
 use ic_stable_structures::storable::Bound;
+use std::collections::BTreeMap;
+use std::time::Duration;
 use std::{borrow::Cow, cell::RefCell};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 const MAX_VALUE_SIZE: u32 = 100;
 
+//default for the longest course name we accept, it can be changed later by a controller with set_max_course_length
+//it has to stay well below MAX_VALUE_SIZE because the candid encoding of the whole Exam must fit in the bound
+const DEFAULT_MAX_COURSE_LENGTH: u32 = 40;
+
+//submissions hold the student's answers so they get a bigger bound than the exam itself
+const MAX_SUBMISSION_SIZE: u32 = 2000;
+//we leave room for the candid header and the other fields of the submission
+const MAX_ANSWERS_LENGTH: u32 = 1800;
+
+//questions in the bank are stored without their answer key, the key has its own map
+const MAX_QUESTION_SIZE: u32 = 1000;
+const MAX_ANSWER_KEY_SIZE: u32 = 100;
+//keeps the question list of an exam and the responses of a submission inside their bounds
+const MAX_QUESTIONS_PER_EXAM: u32 = 50;
+const MAX_QUESTION_LIST_SIZE: u32 = 600;
+
 //we are using methodology and the structure in icp to mange the state bec we dont want to lose the state whenever we redeploy our canister, as if you lose it we will need to experiment with the same data again and again
 
 //candidtype hena mohem 3ashan file .did has text not string and so on , they are not corresponding , so the frontend can know it
@@ -46,10 +69,168 @@ struct Exam {
 //By implementing the Storable trait for Exam, instances of Exam can be easily stored in and retrieved from stable memory using the StableBTreeMap or other stable structures provided by the ic_stable_structures crate.
 //This implementation allows the Exam type to be serialized into bytes and deserialized from bytes, which is essential for storing and retrieving instances of Exam in stable memory.
 
+//errors returned to the frontend when an exam or a participation value is rejected
+//each variant carries the values that made the request invalid so the caller can see what to fix
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
+enum ExamError {
+    //out_of must be at least 1, otherwise no grade can be computed
+    InvalidOutOf,
+    //the curve is added to the grade so it can't be bigger than the full mark
+    CurveExceedsOutOf {
+        curve: u8,
+        out_of: u8,
+    },
+    EmptyCourse,
+    CourseTooLong {
+        length: u32,
+        max: u32,
+    },
+    //the encoded exam doesn't fit in MAX_VALUE_SIZE, inserting it would trap
+    ExamTooLarge {
+        size: u32,
+        max: u32,
+    },
+    //participation is a percentage so it must be between 0 and 100
+    InvalidParticipation {
+        value: u64,
+    },
+    //the new max course length is 0 or would let exams overflow MAX_VALUE_SIZE
+    InvalidMaxCourseLength {
+        max: u32,
+    },
+    AccessRejected,
+    NoSuchExam,
+    //closes_at must be after opens_at and still in the future
+    InvalidWindow {
+        opens_at: u64,
+        closes_at: u64,
+    },
+    NotScheduled,
+    //submitting before opens_at or after closes_at
+    ExamNotOpen {
+        opens_at: u64,
+        closes_at: u64,
+        now: u64,
+    },
+    //the close timer already ran, the schedule can't be changed anymore
+    ExamLocked,
+    //the window and the questions can't change once students can see the exam
+    ExamAlreadyOpened,
+    //grading is only allowed once the exam is locked
+    ExamNotClosed,
+    AnswersTooLong {
+        length: u32,
+        max: u32,
+    },
+    NoSuchSubmission,
+    InvalidMark {
+        mark: u8,
+        out_of: u8,
+    },
+    NoSuchQuestion {
+        id: u64,
+    },
+    EmptyPrompt,
+    //0 points would make the question useless and an exam with only those can't be scored
+    InvalidPoints,
+    //a multiple choice question needs at least two options and none of them empty
+    InvalidOptions,
+    //the answer key is not the kind of answer the question expects, or the option index is out of range
+    InvalidAnswerKey,
+    QuestionTooLarge {
+        size: u32,
+        max: u32,
+    },
+    TooManyQuestions {
+        count: u32,
+        max: u32,
+    },
+    DuplicateQuestion {
+        id: u64,
+    },
+    //the exam has no questions, use submit_answers instead
+    NoQuestions,
+    //the response is for a question that is not in the exam or has the wrong kind of answer
+    InvalidResponse {
+        question: u64,
+    },
+    //the question is part of an exam that already opened, its key can't change under the students' answers
+    QuestionInUse {
+        exam: u64,
+    },
+}
+
+//how a question is answered, the options of a multiple choice question are shown to students
+//numeric answers are right when they are within tolerance of the key
+#[derive(CandidType, Deserialize, Clone)]
+enum QuestionKind {
+    MultipleChoice { options: Vec<String> },
+    TrueFalse,
+    Numeric { tolerance: f64 },
+}
+
+//used both for what a student answers and for the hidden answer key
+#[derive(CandidType, Deserialize, Clone)]
+enum Answer {
+    Choice(u8),
+    Bool(bool),
+    Number(f64),
+}
+
+//the public part of a question, this is what queries return
+#[derive(CandidType, Deserialize, Clone)]
+struct Question {
+    prompt: String,
+    kind: QuestionKind,
+    points: u8,
+}
+
+//what a controller sends to the bank, the answer is split off and never returned
+#[derive(CandidType, Deserialize)]
+struct NewQuestion {
+    prompt: String,
+    kind: QuestionKind,
+    points: u8,
+    answer: Answer,
+}
+
+//the ids of the bank questions that make up an exam, in the order students see them
+#[derive(CandidType, Deserialize, Clone, Default)]
+struct ExamQuestions {
+    questions: Vec<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Response {
+    question: u64,
+    answer: Answer,
+}
+
+//the time window of an exam, times are nanoseconds since the epoch like ic_cdk::api::time()
+//locked is set by the close timer, after that submissions are rejected and grading can start
+#[derive(CandidType, Deserialize, Clone)]
+struct ExamSchedule {
+    opens_at: u64,
+    closes_at: u64,
+    locked: bool,
+}
+
+//what a student handed in, grade stays empty until the exam is graded after close
+//exams made of questions fill responses instead of answers, points is what the auto grading gave them
+//the new fields are options so submissions stored before them still decode
+#[derive(CandidType, Deserialize, Clone)]
+struct Submission {
+    answers: String,
+    submitted_at: u64,
+    grade: Option<u8>,
+    responses: Option<Vec<Response>>,
+    points: Option<u32>,
+}
+
 impl Storable for Exam {
     //The Storable trait is part of the ic_stable_structures crate
     //It returns a Cow<[u8]>, which stands for "copy-on-write" and is a smart pointer that can point to either borrowed data or owned data.
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         //Encode!(self): This macro from the candid crate serializes the Exam instance into a byte vector (Vec<u8>).
         //Cow::Owned(...): Wraps the owned byte vector in a Cow::Owned to return it as Cow<[u8]>.
         Cow::Owned(Encode!(self).unwrap())
@@ -70,6 +251,81 @@ impl Storable for Exam {
     };
 }
 
+impl Storable for ExamSchedule {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VALUE_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for Submission {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_SUBMISSION_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for Question {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_QUESTION_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for Answer {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_ANSWER_KEY_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for ExamQuestions {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_QUESTION_LIST_SIZE,
+        is_fixed_size: false,
+    };
+}
+
 //who we can write this data to our local memoryyyyyy
 
 thread_local! {
@@ -93,6 +349,210 @@ thread_local! {
     static PARTICIPATION_PERCENTAGE_MAP :RefCell<StableBTreeMap<u64,u64,Memory>>= RefCell::new(StableBTreeMap::init(
         MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(1))),
     ));
+
+    //StableCell holds a single value in its own memory location so the setting survives upgrades too
+    static MAX_COURSE_LENGTH :RefCell<StableCell<u32,Memory>>= RefCell::new(StableCell::init(
+        MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(2))),
+        DEFAULT_MAX_COURSE_LENGTH,
+    ).expect("failed to init max course length"));
+
+    //schedule of every exam that has a window, key is the exam key
+    static SCHEDULE_MAP :RefCell<StableBTreeMap<u64,ExamSchedule,Memory>>= RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(3))),
+    ));
+
+    //key is (exam key, student) so all submissions of one exam are next to each other
+    static SUBMISSION_MAP :RefCell<StableBTreeMap<(u64,Principal),Submission,Memory>>= RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(4))),
+    ));
+
+    //timers live on the heap and are lost on upgrade, post_upgrade sets them again from SCHEDULE_MAP
+    //we keep the ids so rescheduling an exam can cancel the old timer
+    static CLOSE_TIMERS :RefCell<BTreeMap<u64,TimerId>>= RefCell::default();
+
+    //the question bank, only the public part of each question
+    static QUESTION_MAP :RefCell<StableBTreeMap<u64,Question,Memory>>= RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(5))),
+    ));
+
+    //answer keys of the bank, same key as QUESTION_MAP
+    //nothing that is exposed to the frontend reads from this map, only the grading does
+    static ANSWER_KEY_MAP :RefCell<StableBTreeMap<u64,Answer,Memory>>= RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(6))),
+    ));
+
+    //exam key -> the questions of that exam
+    static EXAM_QUESTIONS_MAP :RefCell<StableBTreeMap<u64,ExamQuestions,Memory>>= RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(7))),
+    ));
+}
+
+fn is_controller() -> Result<(), ExamError> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(ExamError::AccessRejected)
+    }
+}
+
+//called by the timer when the window ends, after this no more submissions are accepted
+//exams made of questions are graded right after they are locked
+fn close_exam(key: u64) {
+    CLOSE_TIMERS.with(|t| t.borrow_mut().remove(&key));
+    SCHEDULE_MAP.with(|s| {
+        let mut map = s.borrow_mut();
+        if let Some(mut schedule) = map.get(&key) {
+            schedule.locked = true;
+            map.insert(key, schedule);
+        }
+    });
+    auto_grade_exam(key);
+}
+
+//an exam is open from opens_at on and stays that way after it locks
+fn has_opened(schedule: &ExamSchedule, now: u64) -> bool {
+    schedule.locked || now >= schedule.opens_at
+}
+
+//the curve is added on top of the raw mark without going over out_of
+fn curved_grade(exam: &Exam, mark: u8) -> u8 {
+    mark.saturating_add(exam.curve).min(exam.out_of)
+}
+
+fn is_correct(kind: &QuestionKind, key: &Answer, answer: &Answer) -> bool {
+    match (kind, key, answer) {
+        (_, Answer::Choice(k), Answer::Choice(a)) => k == a,
+        (_, Answer::Bool(k), Answer::Bool(a)) => k == a,
+        (QuestionKind::Numeric { tolerance }, Answer::Number(k), Answer::Number(a)) => {
+            (k - a).abs() <= *tolerance
+        }
+        _ => false,
+    }
+}
+
+//the first exam that uses the question and already opened, a new question should get a new id instead
+fn exam_using(id: u64, now: u64) -> Option<u64> {
+    EXAM_QUESTIONS_MAP.with(|q| {
+        q.borrow()
+            .iter()
+            .filter(|(_, exam_questions)| exam_questions.questions.contains(&id))
+            .map(|(key, _)| key)
+            .find(|key| {
+                SCHEDULE_MAP
+                    .with(|s| s.borrow().get(key))
+                    .is_some_and(|schedule| has_opened(&schedule, now))
+            })
+    })
+}
+
+//points scaled to out_of, rounded to the nearest mark
+fn scaled_mark(points: u32, total: u32, out_of: u8) -> u8 {
+    ((points * out_of as u32 + total / 2) / total) as u8
+}
+
+//checks that an answer (a key or a student's response) fits the kind of the question
+fn answer_fits(kind: &QuestionKind, answer: &Answer) -> bool {
+    match (kind, answer) {
+        (QuestionKind::MultipleChoice { options }, Answer::Choice(i)) => {
+            (*i as usize) < options.len()
+        }
+        (QuestionKind::TrueFalse, Answer::Bool(_)) => true,
+        (QuestionKind::Numeric { .. }, Answer::Number(n)) => n.is_finite(),
+        _ => false,
+    }
+}
+
+//scores every submission of the exam against the answer keys
+//the points are scaled to out_of so the result goes through the same curve as a manual mark
+fn auto_grade_exam(key: u64) {
+    let Some(exam) = EXAM_MAP.with(|p| p.borrow().get(&key)) else {
+        return;
+    };
+    let ids = EXAM_QUESTIONS_MAP
+        .with(|q| q.borrow().get(&key))
+        .unwrap_or_default()
+        .questions;
+    if ids.is_empty() {
+        return;
+    }
+    let mut questions = BTreeMap::new();
+    for id in &ids {
+        let question = QUESTION_MAP.with(|q| q.borrow().get(id));
+        let answer_key = ANSWER_KEY_MAP.with(|a| a.borrow().get(id));
+        if let (Some(question), Some(answer_key)) = (question, answer_key) {
+            questions.insert(*id, (question, answer_key));
+        }
+    }
+    let total: u32 = questions.values().map(|(q, _)| q.points as u32).sum();
+    if total == 0 {
+        return;
+    }
+    let start = (key, Principal::from_slice(&[]));
+    SUBMISSION_MAP.with(|s| {
+        let mut map = s.borrow_mut();
+        let graded: Vec<((u64, Principal), Submission)> = map
+            .range(start..)
+            .take_while(|((exam_key, _), _)| *exam_key == key)
+            .filter_map(|(k, mut submission)| {
+                let responses = submission.responses.as_ref()?;
+                let points: u32 = responses
+                    .iter()
+                    .filter_map(|r| {
+                        let (question, answer_key) = questions.get(&r.question)?;
+                        is_correct(&question.kind, answer_key, &r.answer)
+                            .then_some(question.points as u32)
+                    })
+                    .sum();
+                submission.points = Some(points);
+                submission.grade =
+                    Some(curved_grade(&exam, scaled_mark(points, total, exam.out_of)));
+                Some((k, submission))
+            })
+            .collect();
+        for (k, submission) in graded {
+            map.insert(k, submission);
+        }
+    });
+}
+
+//sets the timer that locks the exam at closes_at
+//if closes_at already passed (for example the canister was upgraded during the close) the timer fires right away
+fn arm_close_timer(key: u64, closes_at: u64) {
+    let delay = Duration::from_nanos(closes_at.saturating_sub(ic_cdk::api::time()));
+    let id = ic_cdk_timers::set_timer(delay, move || close_exam(key));
+    if let Some(old) = CLOSE_TIMERS.with(|t| t.borrow_mut().insert(key, id)) {
+        ic_cdk_timers::clear_timer(old);
+    }
+}
+
+//checks the exam before it is written to the map
+//the size check is the last one because it is the one that protects us from the trap in insert
+fn validate_exam(exam: &Exam) -> Result<(), ExamError> {
+    if exam.out_of == 0 {
+        return Err(ExamError::InvalidOutOf);
+    }
+    if exam.curve > exam.out_of {
+        return Err(ExamError::CurveExceedsOutOf {
+            curve: exam.curve,
+            out_of: exam.out_of,
+        });
+    }
+    if exam.course.trim().is_empty() {
+        return Err(ExamError::EmptyCourse);
+    }
+    let max = MAX_COURSE_LENGTH.with(|m| *m.borrow().get());
+    let length = exam.course.len() as u32;
+    if length > max {
+        return Err(ExamError::CourseTooLong { length, max });
+    }
+    let size = exam.to_bytes().len() as u32;
+    if size > MAX_VALUE_SIZE {
+        return Err(ExamError::ExamTooLarge {
+            size,
+            max: MAX_VALUE_SIZE,
+        });
+    }
+    Ok(())
 }
 
 //The provided Rust function get_participation is designed to retrieve a value from a thread-local storage map (PARTICIPATION_PERCENTAGE_MAP) using a key of type u64.
@@ -114,16 +574,525 @@ fn get_exam(key: u64) -> Option<Exam> {
     //.borrow : is borrowing value inside ref cell
     EXAM_MAP.with(|p| p.borrow().get(&key))
 }
+
+#[ic_cdk::query]
+fn get_max_course_length() -> u32 {
+    MAX_COURSE_LENGTH.with(|m| *m.borrow().get())
+}
+
 //after we update here we return last value
-//using options as we will get old values from fns, and here will alter the data
+//the old value is still an option inside the result, the error tells the caller why nothing was written
 #[ic_cdk::update]
-fn insert_exam(key: u64, value: Exam) -> Option<Exam> {
+fn insert_exam(key: u64, value: Exam) -> Result<Option<Exam>, ExamError> {
+    validate_exam(&value)?;
     //borrow mut or not from ref cell , but we will borrow mut as we want to alter the data
-    EXAM_MAP.with(|p| p.borrow_mut().insert(key, value))
+    Ok(EXAM_MAP.with(|p| p.borrow_mut().insert(key, value)))
 }
 
 #[ic_cdk::update]
-fn insert_participation(key: u64, value: u64) -> Option<u64> {
+fn insert_participation(key: u64, value: u64) -> Result<Option<u64>, ExamError> {
+    if value > 100 {
+        return Err(ExamError::InvalidParticipation { value });
+    }
     //borrow mut or not from ref cell
-    PARTICIPATION_PERCENTAGE_MAP.with(|p| p.borrow_mut().insert(key, value))
-}
\ No newline at end of file
+    Ok(PARTICIPATION_PERCENTAGE_MAP.with(|p| p.borrow_mut().insert(key, value)))
+}
+
+//we try the longest possible exam with the new limit so a bad value is rejected here and not later in insert
+fn check_max_course_length(max: u32) -> Result<(), ExamError> {
+    //a course longer than MAX_VALUE_SIZE can't fit anyway, checked first so we don't build a huge string
+    if max == 0 || max > MAX_VALUE_SIZE {
+        return Err(ExamError::InvalidMaxCourseLength { max });
+    }
+    let longest = Exam {
+        out_of: u8::MAX,
+        course: "a".repeat(max as usize),
+        curve: u8::MAX,
+    };
+    if longest.to_bytes().len() as u32 > MAX_VALUE_SIZE {
+        return Err(ExamError::InvalidMaxCourseLength { max });
+    }
+    Ok(())
+}
+
+//only controllers of the canister can change the limit
+#[ic_cdk::update]
+fn set_max_course_length(max: u32) -> Result<(), ExamError> {
+    is_controller()?;
+    check_max_course_length(max)?;
+    MAX_COURSE_LENGTH
+        .with(|m| m.borrow_mut().set(max))
+        .expect("failed to set max course length");
+    Ok(())
+}
+
+//checks a new window for the exam, it can only be moved until the exam opens
+fn check_schedule(key: u64, opens_at: u64, closes_at: u64, now: u64) -> Result<(), ExamError> {
+    if !EXAM_MAP.with(|p| p.borrow().contains_key(&key)) {
+        return Err(ExamError::NoSuchExam);
+    }
+    if opens_at >= closes_at || closes_at <= now {
+        return Err(ExamError::InvalidWindow {
+            opens_at,
+            closes_at,
+        });
+    }
+    if let Some(old) = SCHEDULE_MAP.with(|s| s.borrow().get(&key)) {
+        if old.locked {
+            return Err(ExamError::ExamLocked);
+        }
+        if has_opened(&old, now) {
+            return Err(ExamError::ExamAlreadyOpened);
+        }
+    }
+    Ok(())
+}
+
+//only controllers can set the window of an exam, calling it again before it opens moves the window
+#[ic_cdk::update]
+fn schedule_exam(key: u64, opens_at: u64, closes_at: u64) -> Result<(), ExamError> {
+    is_controller()?;
+    check_schedule(key, opens_at, closes_at, ic_cdk::api::time())?;
+    let schedule = ExamSchedule {
+        opens_at,
+        closes_at,
+        locked: false,
+    };
+    SCHEDULE_MAP.with(|s| s.borrow_mut().insert(key, schedule));
+    arm_close_timer(key, closes_at);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_schedule(key: u64) -> Option<ExamSchedule> {
+    SCHEDULE_MAP.with(|s| s.borrow().get(&key))
+}
+
+//returns the time of the submission if the exam is inside its window
+fn check_open(key: u64) -> Result<u64, ExamError> {
+    let schedule = SCHEDULE_MAP
+        .with(|s| s.borrow().get(&key))
+        .ok_or(ExamError::NotScheduled)?;
+    let now = ic_cdk::api::time();
+    if schedule.locked || now < schedule.opens_at || now >= schedule.closes_at {
+        return Err(ExamError::ExamNotOpen {
+            opens_at: schedule.opens_at,
+            closes_at: schedule.closes_at,
+            now,
+        });
+    }
+    Ok(now)
+}
+
+//the caller submits for themselves, sending again inside the window replaces the old answers
+#[ic_cdk::update]
+fn submit_answers(key: u64, answers: String) -> Result<(), ExamError> {
+    let now = check_open(key)?;
+    let length = answers.len() as u32;
+    if length > MAX_ANSWERS_LENGTH {
+        return Err(ExamError::AnswersTooLong {
+            length,
+            max: MAX_ANSWERS_LENGTH,
+        });
+    }
+    let submission = Submission {
+        answers,
+        submitted_at: now,
+        grade: None,
+        responses: None,
+        points: None,
+    };
+    SUBMISSION_MAP.with(|s| s.borrow_mut().insert((key, ic_cdk::caller()), submission));
+    Ok(())
+}
+
+//a student can only read their own submission, controllers can read all of them
+#[ic_cdk::query]
+fn get_submission(key: u64, student: Principal) -> Result<Submission, ExamError> {
+    let caller = ic_cdk::caller();
+    if caller != student && !ic_cdk::api::is_controller(&caller) {
+        return Err(ExamError::AccessRejected);
+    }
+    SUBMISSION_MAP
+        .with(|s| s.borrow().get(&(key, student)))
+        .ok_or(ExamError::NoSuchSubmission)
+}
+
+//grading runs after close, mark is the raw mark and the curve is added on top of it
+//this also works on exams with questions, to correct the auto grade by hand
+#[ic_cdk::update]
+fn grade_submission(key: u64, student: Principal, mark: u8) -> Result<u8, ExamError> {
+    is_controller()?;
+    let exam = EXAM_MAP
+        .with(|p| p.borrow().get(&key))
+        .ok_or(ExamError::NoSuchExam)?;
+    let schedule = SCHEDULE_MAP
+        .with(|s| s.borrow().get(&key))
+        .ok_or(ExamError::NotScheduled)?;
+    if !schedule.locked {
+        return Err(ExamError::ExamNotClosed);
+    }
+    if mark > exam.out_of {
+        return Err(ExamError::InvalidMark {
+            mark,
+            out_of: exam.out_of,
+        });
+    }
+    SUBMISSION_MAP.with(|s| {
+        let mut map = s.borrow_mut();
+        let mut submission = map
+            .get(&(key, student))
+            .ok_or(ExamError::NoSuchSubmission)?;
+        let grade = curved_grade(&exam, mark);
+        submission.grade = Some(grade);
+        map.insert((key, student), submission);
+        Ok(grade)
+    })
+}
+
+//adds a question to the bank or replaces it, only controllers can see or set answer keys
+//a question can't be replaced once an exam using it opened
+#[ic_cdk::update]
+fn insert_question(id: u64, question: NewQuestion) -> Result<(), ExamError> {
+    is_controller()?;
+    if let Some(exam) = exam_using(id, ic_cdk::api::time()) {
+        return Err(ExamError::QuestionInUse { exam });
+    }
+    if question.prompt.trim().is_empty() {
+        return Err(ExamError::EmptyPrompt);
+    }
+    if question.points == 0 {
+        return Err(ExamError::InvalidPoints);
+    }
+    if let QuestionKind::MultipleChoice { options } = &question.kind {
+        if options.len() < 2
+            || options.len() > u8::MAX as usize
+            || options.iter().any(|o| o.trim().is_empty())
+        {
+            return Err(ExamError::InvalidOptions);
+        }
+    }
+    if let QuestionKind::Numeric { tolerance } = &question.kind {
+        if !tolerance.is_finite() || *tolerance < 0.0 {
+            return Err(ExamError::InvalidAnswerKey);
+        }
+    }
+    if !answer_fits(&question.kind, &question.answer) {
+        return Err(ExamError::InvalidAnswerKey);
+    }
+    let public = Question {
+        prompt: question.prompt,
+        kind: question.kind,
+        points: question.points,
+    };
+    let size = public.to_bytes().len() as u32;
+    if size > MAX_QUESTION_SIZE {
+        return Err(ExamError::QuestionTooLarge {
+            size,
+            max: MAX_QUESTION_SIZE,
+        });
+    }
+    QUESTION_MAP.with(|q| q.borrow_mut().insert(id, public));
+    ANSWER_KEY_MAP.with(|a| a.borrow_mut().insert(id, question.answer));
+    Ok(())
+}
+
+//returns the question without its answer key
+#[ic_cdk::query]
+fn get_question(id: u64) -> Option<Question> {
+    QUESTION_MAP.with(|q| q.borrow().get(&id))
+}
+
+//sets the questions of an exam, this is not allowed anymore once the exam opened
+#[ic_cdk::update]
+fn set_exam_questions(key: u64, questions: Vec<u64>) -> Result<(), ExamError> {
+    is_controller()?;
+    if !EXAM_MAP.with(|p| p.borrow().contains_key(&key)) {
+        return Err(ExamError::NoSuchExam);
+    }
+    if let Some(schedule) = SCHEDULE_MAP.with(|s| s.borrow().get(&key)) {
+        if has_opened(&schedule, ic_cdk::api::time()) {
+            return Err(ExamError::ExamAlreadyOpened);
+        }
+    }
+    let count = questions.len() as u32;
+    if count > MAX_QUESTIONS_PER_EXAM {
+        return Err(ExamError::TooManyQuestions {
+            count,
+            max: MAX_QUESTIONS_PER_EXAM,
+        });
+    }
+    for (i, id) in questions.iter().enumerate() {
+        if !QUESTION_MAP.with(|q| q.borrow().contains_key(id)) {
+            return Err(ExamError::NoSuchQuestion { id: *id });
+        }
+        if questions[..i].contains(id) {
+            return Err(ExamError::DuplicateQuestion { id: *id });
+        }
+    }
+    EXAM_QUESTIONS_MAP.with(|q| q.borrow_mut().insert(key, ExamQuestions { questions }));
+    Ok(())
+}
+
+//students only see the questions once the exam opened, controllers can see them anytime
+#[ic_cdk::query]
+fn get_exam_questions(key: u64) -> Result<Vec<(u64, Question)>, ExamError> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        let schedule = SCHEDULE_MAP
+            .with(|s| s.borrow().get(&key))
+            .ok_or(ExamError::NotScheduled)?;
+        let now = ic_cdk::api::time();
+        if now < schedule.opens_at {
+            return Err(ExamError::ExamNotOpen {
+                opens_at: schedule.opens_at,
+                closes_at: schedule.closes_at,
+                now,
+            });
+        }
+    }
+    let ids = EXAM_QUESTIONS_MAP
+        .with(|q| q.borrow().get(&key))
+        .unwrap_or_default()
+        .questions;
+    Ok(ids
+        .into_iter()
+        .filter_map(|id| QUESTION_MAP.with(|q| q.borrow().get(&id)).map(|q| (id, q)))
+        .collect())
+}
+
+//answers for an exam made of questions, they are scored when the exam closes
+#[ic_cdk::update]
+fn submit_responses(key: u64, responses: Vec<Response>) -> Result<(), ExamError> {
+    let now = check_open(key)?;
+    let ids = EXAM_QUESTIONS_MAP
+        .with(|q| q.borrow().get(&key))
+        .unwrap_or_default()
+        .questions;
+    if ids.is_empty() {
+        return Err(ExamError::NoQuestions);
+    }
+    for (i, response) in responses.iter().enumerate() {
+        let question = ids
+            .contains(&response.question)
+            .then(|| QUESTION_MAP.with(|q| q.borrow().get(&response.question)))
+            .flatten()
+            .ok_or(ExamError::InvalidResponse {
+                question: response.question,
+            })?;
+        let duplicate = responses[..i]
+            .iter()
+            .any(|r| r.question == response.question);
+        if duplicate || !answer_fits(&question.kind, &response.answer) {
+            return Err(ExamError::InvalidResponse {
+                question: response.question,
+            });
+        }
+    }
+    let submission = Submission {
+        answers: String::new(),
+        submitted_at: now,
+        grade: None,
+        responses: Some(responses),
+        points: None,
+    };
+    SUBMISSION_MAP.with(|s| s.borrow_mut().insert((key, ic_cdk::caller()), submission));
+    Ok(())
+}
+
+//the schedule is in stable memory but the timers are not, so every exam that is still open gets its timer back
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    let pending: Vec<(u64, u64)> = SCHEDULE_MAP.with(|s| {
+        s.borrow()
+            .iter()
+            .filter(|(_, schedule)| !schedule.locked)
+            .map(|(key, schedule)| (key, schedule.closes_at))
+            .collect()
+    });
+    for (key, closes_at) in pending {
+        arm_close_timer(key, closes_at);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exam(out_of: u8, course: &str, curve: u8) -> Exam {
+        Exam {
+            out_of,
+            course: course.to_string(),
+            curve,
+        }
+    }
+
+    #[test]
+    fn exams_are_validated_before_insert() {
+        assert_eq!(validate_exam(&exam(10, "math", 2)), Ok(()));
+        assert_eq!(
+            validate_exam(&exam(0, "math", 0)),
+            Err(ExamError::InvalidOutOf)
+        );
+        assert_eq!(
+            validate_exam(&exam(10, "math", 11)),
+            Err(ExamError::CurveExceedsOutOf {
+                curve: 11,
+                out_of: 10
+            })
+        );
+        assert_eq!(
+            validate_exam(&exam(10, "  ", 0)),
+            Err(ExamError::EmptyCourse)
+        );
+        let long = "a".repeat(DEFAULT_MAX_COURSE_LENGTH as usize + 1);
+        assert_eq!(
+            validate_exam(&exam(10, &long, 0)),
+            Err(ExamError::CourseTooLong {
+                length: DEFAULT_MAX_COURSE_LENGTH + 1,
+                max: DEFAULT_MAX_COURSE_LENGTH
+            })
+        );
+    }
+
+    #[test]
+    fn windows_can_only_move_until_the_exam_opens() {
+        assert_eq!(check_schedule(1, 10, 20, 0), Err(ExamError::NoSuchExam));
+        EXAM_MAP.with(|p| p.borrow_mut().insert(1, exam(10, "math", 0)));
+        assert_eq!(
+            check_schedule(1, 20, 10, 0),
+            Err(ExamError::InvalidWindow {
+                opens_at: 20,
+                closes_at: 10
+            })
+        );
+        assert_eq!(
+            check_schedule(1, 10, 20, 20),
+            Err(ExamError::InvalidWindow {
+                opens_at: 10,
+                closes_at: 20
+            })
+        );
+        assert_eq!(check_schedule(1, 10, 20, 0), Ok(()));
+        let schedule = ExamSchedule {
+            opens_at: 10,
+            closes_at: 20,
+            locked: false,
+        };
+        SCHEDULE_MAP.with(|s| s.borrow_mut().insert(1, schedule.clone()));
+        //before it opens the window can still move
+        assert_eq!(check_schedule(1, 30, 40, 9), Ok(()));
+        //once it opened, moving opens_at back into the future would hide it again
+        assert_eq!(
+            check_schedule(1, 30, 40, 10),
+            Err(ExamError::ExamAlreadyOpened)
+        );
+        let locked = ExamSchedule {
+            locked: true,
+            ..schedule
+        };
+        SCHEDULE_MAP.with(|s| s.borrow_mut().insert(1, locked));
+        assert_eq!(check_schedule(1, 30, 40, 0), Err(ExamError::ExamLocked));
+    }
+
+    #[test]
+    fn participation_is_a_percentage() {
+        assert_eq!(insert_participation(1, 100), Ok(None));
+        assert_eq!(
+            insert_participation(1, 101),
+            Err(ExamError::InvalidParticipation { value: 101 })
+        );
+        assert_eq!(get_participation(1), Some(100));
+    }
+
+    #[test]
+    fn max_course_length_must_leave_room_for_the_exam() {
+        assert_eq!(check_max_course_length(DEFAULT_MAX_COURSE_LENGTH), Ok(()));
+        for max in [0, MAX_VALUE_SIZE, MAX_VALUE_SIZE + 1, u32::MAX] {
+            assert_eq!(
+                check_max_course_length(max),
+                Err(ExamError::InvalidMaxCourseLength { max })
+            );
+        }
+    }
+
+    #[test]
+    fn answers_are_checked_against_the_key() {
+        let choice = QuestionKind::MultipleChoice {
+            options: vec!["a".to_string(), "b".to_string()],
+        };
+        assert!(is_correct(&choice, &Answer::Choice(1), &Answer::Choice(1)));
+        assert!(!is_correct(&choice, &Answer::Choice(1), &Answer::Choice(0)));
+        assert!(is_correct(
+            &QuestionKind::TrueFalse,
+            &Answer::Bool(true),
+            &Answer::Bool(true)
+        ));
+        let numeric = QuestionKind::Numeric { tolerance: 0.5 };
+        assert!(is_correct(
+            &numeric,
+            &Answer::Number(3.0),
+            &Answer::Number(3.5)
+        ));
+        assert!(!is_correct(
+            &numeric,
+            &Answer::Number(3.0),
+            &Answer::Number(3.6)
+        ));
+        //an answer of the wrong kind is never right
+        assert!(!is_correct(
+            &choice,
+            &Answer::Choice(1),
+            &Answer::Bool(true)
+        ));
+    }
+
+    #[test]
+    fn answers_fit_the_kind_of_question() {
+        let choice = QuestionKind::MultipleChoice {
+            options: vec!["a".to_string(), "b".to_string()],
+        };
+        assert!(answer_fits(&choice, &Answer::Choice(1)));
+        assert!(!answer_fits(&choice, &Answer::Choice(2)));
+        assert!(!answer_fits(&choice, &Answer::Bool(true)));
+        assert!(answer_fits(&QuestionKind::TrueFalse, &Answer::Bool(false)));
+        let numeric = QuestionKind::Numeric { tolerance: 0.0 };
+        assert!(answer_fits(&numeric, &Answer::Number(1.5)));
+        assert!(!answer_fits(&numeric, &Answer::Number(f64::NAN)));
+    }
+
+    #[test]
+    fn points_are_scaled_and_rounded() {
+        assert_eq!(scaled_mark(0, 3, 10), 0);
+        assert_eq!(scaled_mark(3, 3, 10), 10);
+        //2/3 of 10 is 6.67
+        assert_eq!(scaled_mark(2, 3, 10), 7);
+        //1/3 of 10 is 3.33
+        assert_eq!(scaled_mark(1, 3, 10), 3);
+        //half way rounds up
+        assert_eq!(scaled_mark(1, 4, 10), 3);
+        assert_eq!(scaled_mark(50, 50, u8::MAX), u8::MAX);
+        let exam = Exam {
+            out_of: 10,
+            course: "math".to_string(),
+            curve: 2,
+        };
+        assert_eq!(curved_grade(&exam, scaled_mark(2, 3, exam.out_of)), 9);
+        assert_eq!(curved_grade(&exam, scaled_mark(3, 3, exam.out_of)), 10);
+    }
+
+    #[test]
+    fn exams_open_at_opens_at_and_stay_open_once_locked() {
+        let schedule = ExamSchedule {
+            opens_at: 10,
+            closes_at: 20,
+            locked: false,
+        };
+        assert!(!has_opened(&schedule, 9));
+        assert!(has_opened(&schedule, 10));
+        let locked = ExamSchedule {
+            locked: true,
+            ..schedule
+        };
+        assert!(has_opened(&locked, 0));
+    }
+}
@@ -0,0 +1,136 @@
+// Types shared by the publisher and the subscriber, both canisters put them on the wire
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+// Define the Counter struct with two fields: topic and value
+// it is what "publish" takes and what the old "update_count" callback receives
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Counter {
+    pub topic: String,
+    pub value: u64,
+}
+
+// the content of a message, either a counter value or any Candid encoded value the subscriber knows how to decode
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Payload {
+    Counter(u64),
+    Candid(Vec<u8>),
+}
+
+// what a subscriber's callback receives for every message
+// seq is counted per subscriber, so a subscriber can drop a message it already got
+// publisher is the principal that called publish, timestamp is when it did
+// topic_seq is the message's place in the publisher's log of the topic, it is what subscribe_from and fetch_since take
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Envelope {
+    pub topic: String,
+    pub seq: u64,
+    pub timestamp: u64,
+    pub publisher: Principal,
+    pub payload: Payload,
+    pub topic_seq: Option<u64>,
+}
+
+// Define the Subscriber struct with the topic, an optional filter on the value and the callback method
+// each call to subscribe adds one topic to the ones the caller already has
+// topics are split in levels by '/', a '*' level matches any one level and a last '**' level matches
+// whatever is left, so "scores/math/*" gets "scores/math/algebra" and "scores/**" gets everything under scores
+// the callback is called with an Envelope, without one the subscriber gets "update_count" with a Counter
+// with a ttl the subscription ends after that many seconds unless it is renewed by subscribing again
+// with batch_seconds the messages are held that long and the callback gets them together as a vec of Envelope
+// with from_seq the logged messages of the topic after that topic_seq are sent first, 0 sends the whole log
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Subscriber {
+    pub topic: String,
+    pub filter: Option<Filter>,
+    pub callback: Option<String>,
+    pub ttl_seconds: Option<u64>,
+    pub batch_seconds: Option<u64>,
+    pub from_seq: Option<u64>,
+}
+
+// what subscribe answers, renewed is true when the subscription already existed
+// replayed is the number of logged messages queued because of from_seq
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct SubscriptionStatus {
+    pub topic: String,
+    pub expires_at: Option<u64>,
+    pub renewed: bool,
+    pub replayed: u64,
+}
+
+// what fetch_since answers, the messages have their topic_seq in seq too
+// first_seq is the oldest message still in the log, whoever asked for less than first_seq - 1 missed some
+// last_seq is the last message published on the topic, 0 when there was none
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct LogPage {
+    pub messages: Vec<Envelope>,
+    pub first_seq: Option<u64>,
+    pub last_seq: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum SubscribeError {
+    // too long, or '**' before the last level
+    InvalidTopic,
+    // the callback method name is empty or too long
+    InvalidCallback,
+    // the topic's ACL doesn't list the subscriber
+    NotAllowed,
+    // the batch window is 0 or too long, or there is no callback to send the batch to
+    InvalidBatch,
+    // from_seq was given for a topic with wildcards, the log is numbered per topic
+    InvalidReplay,
+    // only used by the subscriber, the publisher could not be called
+    CallFailed(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Comparison {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+}
+
+// a message is only sent when `counter value <comparison> value` holds, e.g. value > 10
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Filter {
+    pub comparison: Comparison,
+    pub value: u64,
+}
+
+impl Filter {
+    // there is nothing to compare in a Candid payload, filtered subscriptions never get one
+    pub fn accepts(&self, payload: &Payload) -> bool {
+        let Payload::Counter(value) = *payload else {
+            return false;
+        };
+        match self.comparison {
+            Comparison::Greater => value > self.value,
+            Comparison::GreaterOrEqual => value >= self.value,
+            Comparison::Less => value < self.value,
+            Comparison::LessOrEqual => value <= self.value,
+            Comparison::Equal => value == self.value,
+            Comparison::NotEqual => value != self.value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_compare_the_value() {
+        let filter = Filter {
+            comparison: Comparison::Greater,
+            value: 10,
+        };
+        assert!(filter.accepts(&Payload::Counter(11)));
+        assert!(!filter.accepts(&Payload::Counter(10)));
+        assert!(!filter.accepts(&Payload::Candid(Vec::new())));
+    }
+}
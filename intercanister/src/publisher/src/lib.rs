@@ -1,52 +1,1611 @@
-use candid::{CandidType, Principal};
-use ic_cdk::update;
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_cdk::api::call::{self, RejectionCode};
+use ic_cdk::{init, post_upgrade, query, update};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use messages::{
+    Counter, Envelope, Filter, LogPage, Payload, SubscribeError, Subscriber, SubscriptionStatus,
+};
 use serde::Deserialize;
+use std::borrow::Cow;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
-// Define a type alias for a BTreeMap that maps a Principal to a Subscriber
-type SubscriberStore = BTreeMap<Principal, Subscriber>;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
 
-//to store subscribers 
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+// Define a type alias for a StableBTreeMap that maps a Principal to the topics it subscribed to
+// it lives in stable memory, so subscriptions are kept when the canister is upgraded
+// the heap map before it had no pre_upgrade hook, its subscribers are lost on the upgrade to this version and have to subscribe again
+type SubscriberStore = StableBTreeMap<Principal, Subscriptions, Memory>;
+// the same subscriptions ordered by topic, publish reads the subscribers of one topic from here
+// subscriptions with wildcards use the same type in PATTERN_INDEX
+type TopicIndex = StableBTreeMap<TopicKey, (), Memory>;
+// the payload filter of a subscription, subscriptions without one are not in here
+type FilterStore = StableBTreeMap<TopicKey, StoredFilter, Memory>;
+// the callback method of a subscription, subscriptions without one get "update_count"
+type CallbackStore = StableBTreeMap<TopicKey, String, Memory>;
+// principals allowed to publish besides the controllers
+type PublisherStore = StableBTreeMap<Principal, (), Memory>;
+// who may publish and subscribe on a topic, topics without an entry are open
+type AclStore = StableBTreeMap<String, TopicAcl, Memory>;
+// when a subscription ends, subscriptions without a ttl are not in here
+type ExpiryStore = StableBTreeMap<TopicKey, u64, Memory>;
+// dead letters in a row of each subscriber, a delivered message resets it
+type FailureStore = StableBTreeMap<Principal, u32, Memory>;
+// the batch window of a subscription in seconds, subscriptions without one get every message on its own
+type BatchWindowStore = StableBTreeMap<TopicKey, u64, Memory>;
+// what delivering each published topic cost so far
+type UsageStore = StableBTreeMap<String, TopicUsage, Memory>;
+// the last MAX_LOG_LENGTH messages of every topic, for subscribers that want what they missed
+type LogStore = StableBTreeMap<LogKey, StoredEnvelope, Memory>;
+// last topic_seq given on each topic
+type TopicSequenceStore = StableBTreeMap<String, u64, Memory>;
+// messages not delivered yet, key is (subscriber, sequence number)
+type Outbox = StableBTreeMap<(Principal, u64), OutboxEntry, Memory>;
+// last sequence number given to each subscriber
+type SequenceStore = StableBTreeMap<Principal, u64, Memory>;
+// messages that failed MAX_DELIVERY_ATTEMPTS times, key is (subscriber, sequence number)
+type DeadLetterStore = StableBTreeMap<(Principal, u64), DeadLetter, Memory>;
+
+// memory 0 held the one topic per subscriber map, its entries don't decode as Subscriptions so it is not reused
+const SUBSCRIBERS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const TOPIC_INDEX_MEMORY_ID: MemoryId = MemoryId::new(2);
+const OUTBOX_MEMORY_ID: MemoryId = MemoryId::new(3);
+const SEQUENCES_MEMORY_ID: MemoryId = MemoryId::new(4);
+const DEAD_LETTERS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const PATTERN_INDEX_MEMORY_ID: MemoryId = MemoryId::new(6);
+const FILTERS_MEMORY_ID: MemoryId = MemoryId::new(7);
+const CALLBACKS_MEMORY_ID: MemoryId = MemoryId::new(8);
+const PUBLISHERS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const ACLS_MEMORY_ID: MemoryId = MemoryId::new(10);
+const EXPIRIES_MEMORY_ID: MemoryId = MemoryId::new(11);
+const FAILURES_MEMORY_ID: MemoryId = MemoryId::new(12);
+const BATCH_WINDOWS_MEMORY_ID: MemoryId = MemoryId::new(13);
+const USAGE_MEMORY_ID: MemoryId = MemoryId::new(14);
+const LOG_MEMORY_ID: MemoryId = MemoryId::new(15);
+const TOPIC_SEQUENCES_MEMORY_ID: MemoryId = MemoryId::new(16);
+
+// a message goes to the dead letters after this many failed calls
+const MAX_DELIVERY_ATTEMPTS: u32 = 5;
+// the wait before a retry doubles with every failure, starting at this
+const RETRY_BASE_SECONDS: u64 = 2;
+// a subscriber is dropped with all its subscriptions after this many dead letters in a row
+const MAX_DEAD_LETTERS_IN_A_ROW: u32 = 3;
+// how often expired subscriptions are removed, publish already skips them in between
+const EXPIRY_SWEEP_SECONDS: u64 = 300;
+// longest batch window a subscription can ask for
+const MAX_BATCH_SECONDS: u64 = 600;
+// most messages sent in one call, the rest go in the next one
+const MAX_BATCH_SIZE: usize = 50;
+// fees of a call to a canister on another subnet, the usage is estimated with them
+const CALL_FEE: u128 = 260_000;
+const BYTE_FEE: u128 = 1_000;
+// messages kept in the log of each topic, older ones are dropped
+const MAX_LOG_LENGTH: u64 = 1000;
+// most messages fetch_since returns at once
+const MAX_FETCH_PAGE: usize = 100;
+
+// topics are part of the index keys, which need a size limit
+const MAX_TOPIC_LENGTH: usize = 100;
+const MAX_TOPIC_KEY_SIZE: u32 = 200;
+const MAX_FILTER_SIZE: u32 = 50;
+const MAX_CALLBACK_LENGTH: usize = 100;
+// Candid payloads wait in the outbox of every subscriber, so they are kept small
+const MAX_PAYLOAD_SIZE: usize = 4096;
+
+//to store subscribers
 thread_local! {
-    static SUBSCRIBERS: RefCell<SubscriberStore> = RefCell::default();
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static SUBSCRIBERS: RefCell<SubscriberStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(init_subscribers(&m.borrow())));
+
+    static TOPIC_INDEX: RefCell<TopicIndex> =
+        MEMORY_MANAGER.with(|m| RefCell::new(init_topic_index(&m.borrow())));
+
+    // wildcard subscriptions can't be found by topic, publish checks all of them
+    static PATTERN_INDEX: RefCell<TopicIndex> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(PATTERN_INDEX_MEMORY_ID))));
+
+    static FILTERS: RefCell<FilterStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(FILTERS_MEMORY_ID))));
+
+    static CALLBACKS: RefCell<CallbackStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(CALLBACKS_MEMORY_ID))));
+
+    static PUBLISHERS: RefCell<PublisherStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(PUBLISHERS_MEMORY_ID))));
+
+    static ACLS: RefCell<AclStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(ACLS_MEMORY_ID))));
+
+    static EXPIRIES: RefCell<ExpiryStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(EXPIRIES_MEMORY_ID))));
+
+    static FAILURES: RefCell<FailureStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(FAILURES_MEMORY_ID))));
+
+    static BATCH_WINDOWS: RefCell<BatchWindowStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(BATCH_WINDOWS_MEMORY_ID))));
+
+    static USAGE: RefCell<UsageStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(USAGE_MEMORY_ID))));
+
+    static LOG: RefCell<LogStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(LOG_MEMORY_ID))));
+
+    static TOPIC_SEQUENCES: RefCell<TopicSequenceStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(TOPIC_SEQUENCES_MEMORY_ID))));
+
+    static OUTBOX: RefCell<Outbox> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(OUTBOX_MEMORY_ID))));
+
+    static SEQUENCES: RefCell<SequenceStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(SEQUENCES_MEMORY_ID))));
+
+    static DEAD_LETTERS: RefCell<DeadLetterStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(DEAD_LETTERS_MEMORY_ID))));
+
+    // subscribers with a call in flight or a retry timer set, only one delivery runs per subscriber
+    // so the messages arrive in sequence order
+    static DELIVERING: RefCell<BTreeSet<Principal>> = RefCell::default();
+
+    // subscribers with a timer set for the end of their batch window
+    static FLUSHING: RefCell<BTreeSet<Principal>> = RefCell::default();
 }
-// Define the Counter struct with two fields: topic and value
-#[derive(Clone, Debug, CandidType, Deserialize)]
-struct Counter {
+// Filter comes from the messages crate, it is stored through this wrapper with the same encoding
+#[derive(Clone, Debug)]
+struct StoredFilter(Filter);
+
+// same for the envelopes in the log
+#[derive(Clone, Debug)]
+struct StoredEnvelope(Envelope);
+
+// key of the log, the messages of a topic are next to each other in publish order
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+struct LogKey {
+    topic: String,
+    seq: u64,
+}
+
+// every topic a subscriber is subscribed to
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct Subscriptions {
+    topics: BTreeSet<String>,
+}
+
+// key of the topic index, ordered by topic first so the subscribers of a topic are next to each other
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+struct TopicKey {
     topic: String,
-    value: u64,
+    subscriber: Principal,
+}
+
+// access rules of one topic, None leaves that side open
+// publishers in the list still have to be allowed to publish at all
+// the subscribers list is checked again for every message, so a wildcard subscription can't get around it
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct TopicAcl {
+    publishers: Option<BTreeSet<Principal>>,
+    subscribers: Option<BTreeSet<Principal>>,
+}
+
+// why publish or publish_message refused a message, nothing was queued
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+enum PublishError {
+    // the caller is not a controller and was not added with add_publisher
+    NotPublisher,
+    // the topic's ACL doesn't list the caller
+    NotAllowed,
+    // a Candid payload longer than MAX_PAYLOAD_SIZE
+    PayloadTooLarge,
+}
+
+// a message waiting in the outbox
+// entries queued before envelopes existed only have the counter
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct OutboxEntry {
+    counter: Option<Counter>,
+    envelope: Option<Envelope>,
+    // the method the envelope goes to, None sends the counter to "update_count"
+    callback: Option<String>,
+    attempts: u32,
+    last_error: Option<String>,
+    // sent together with the next batched entries of the same callback
+    batched: Option<bool>,
+    // the publisher's cycles that go with the message to the subscriber
+    cycles: Option<u128>,
 }
-// Define the Subscriber struct with one field: topic
+
+// a message that was given up on, redeliver_dead_letters puts it back in the outbox
 #[derive(Clone, Debug, CandidType, Deserialize)]
-struct Subscriber {
+struct DeadLetter {
+    counter: Option<Counter>,
+    envelope: Option<Envelope>,
+    callback: Option<String>,
+    attempts: u32,
+    last_error: String,
+    failed_at: u64,
+    batched: Option<bool>,
+    cycles: Option<u128>,
+}
+
+// how a message is sent to one subscriber
+#[derive(Clone, Debug, Default)]
+struct Delivery {
+    callback: Option<String>,
+    batched: bool,
+    cycles: u128,
+}
+
+// counted for the topic a message was published on
+// sends counts every message sent to a subscriber, retries included, a batch sends several in one call
+// estimated_cycles comes from CALL_FEE and BYTE_FEE, it is not measured: the balance also moves with other calls
+// in flight and with refunds, so its change around one call can't be put on one topic
+// cycles_attached is what publishers paid for the subscribers
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct TopicUsage {
+    messages: u64,
+    sends: u64,
+    bytes: u64,
+    estimated_cycles: u128,
+    cycles_attached: u128,
+}
+
+impl OutboxEntry {
+    // what "update_count" receives, Candid payloads have no counter
+    fn counter(&self) -> Option<Counter> {
+        if let Some(counter) = &self.counter {
+            return Some(counter.clone());
+        }
+        let envelope = self.envelope.as_ref()?;
+        let Payload::Counter(value) = envelope.payload else {
+            return None;
+        };
+        Some(Counter {
+            topic: envelope.topic.clone(),
+            value,
+        })
+    }
+}
+
+impl Storable for Subscriptions {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for TopicAcl {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for OutboxEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for TopicUsage {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for DeadLetter {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for StoredFilter {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StoredFilter(Decode!(bytes.as_ref(), Filter).unwrap())
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_FILTER_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for StoredEnvelope {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StoredEnvelope(Decode!(bytes.as_ref(), Envelope).unwrap())
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for LogKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_TOPIC_KEY_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for TopicKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_TOPIC_KEY_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+// init reads back whatever is already in the memory, that is how the maps survive an upgrade
+fn init_subscribers(memory_manager: &MemoryManager<DefaultMemoryImpl>) -> SubscriberStore {
+    StableBTreeMap::init(memory_manager.get(SUBSCRIBERS_MEMORY_ID))
+}
+
+fn init_topic_index(memory_manager: &MemoryManager<DefaultMemoryImpl>) -> TopicIndex {
+    StableBTreeMap::init(memory_manager.get(TOPIC_INDEX_MEMORY_ID))
+}
+
+// adds the topic to both maps, subscribing twice to the same topic changes nothing
+fn add_subscription(
+    subscribers: &mut SubscriberStore,
+    topic_index: &mut TopicIndex,
+    subscriber: Principal,
     topic: String,
+) {
+    let mut subscriptions = subscribers.get(&subscriber).unwrap_or_default();
+    subscriptions.topics.insert(topic.clone());
+    subscribers.insert(subscriber, subscriptions);
+    topic_index.insert(TopicKey { topic, subscriber }, ());
+}
+
+fn is_pattern(topic: &str) -> bool {
+    topic.split('/').any(|level| level == "*" || level == "**")
+}
+
+// '**' means the rest of the topic, so it can only be the last level
+fn validate_topic(topic: &str) -> bool {
+    let mut levels = topic.split('/').rev();
+    levels.next();
+    topic.len() <= MAX_TOPIC_LENGTH && levels.all(|level| level != "**")
+}
+
+fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for pattern_level in pattern.split('/') {
+        match pattern_level {
+            "**" => return true,
+            "*" => {
+                if levels.next().is_none() {
+                    return false;
+                }
+            }
+            literal => {
+                if levels.next() != Some(literal) {
+                    return false;
+                }
+            }
+        }
+    }
+    levels.next().is_none()
+}
+
+fn check_controller() {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("only controllers can do this");
+    }
 }
 
+fn topic_acl(topic: &str) -> Option<TopicAcl> {
+    ACLS.with(|acls| acls.borrow().get(&topic.to_string()))
+}
+
+// controllers can always publish, anyone else has to be added with add_publisher first
+fn check_publisher(topic: &str) -> Result<(), PublishError> {
+    let caller = ic_cdk::caller();
+    if ic_cdk::api::is_controller(&caller) {
+        return Ok(());
+    }
+    check_allowed_publisher(&caller, topic)
+}
+
+// the allowlist and then the topic's ACL, controllers don't go through this
+fn check_allowed_publisher(caller: &Principal, topic: &str) -> Result<(), PublishError> {
+    if !PUBLISHERS.with(|p| p.borrow().contains_key(caller)) {
+        return Err(PublishError::NotPublisher);
+    }
+    if !may_publish(topic_acl(topic).as_ref(), caller) {
+        return Err(PublishError::NotAllowed);
+    }
+    Ok(())
+}
+
+fn may_publish(acl: Option<&TopicAcl>, principal: &Principal) -> bool {
+    acl.and_then(|acl| acl.publishers.as_ref())
+        .is_none_or(|publishers| publishers.contains(principal))
+}
+
+fn may_subscribe(acl: Option<&TopicAcl>, principal: &Principal) -> bool {
+    acl.and_then(|acl| acl.subscribers.as_ref())
+        .is_none_or(|subscribers| subscribers.contains(principal))
+}
+
+// the first key a topic can have, principals can't be shorter than empty
+fn topic_start(topic: &str) -> TopicKey {
+    TopicKey {
+        topic: topic.to_string(),
+        subscriber: Principal::from_slice(&[]),
+    }
+}
+
+// puts the message at the end of the subscriber's outbox, sequence numbers start at 1
+fn enqueue(
+    outbox: &mut Outbox,
+    sequences: &mut SequenceStore,
+    subscriber: Principal,
+    mut envelope: Envelope,
+    delivery: Delivery,
+) -> u64 {
+    let seq = sequences.get(&subscriber).unwrap_or(0) + 1;
+    sequences.insert(subscriber, seq);
+    envelope.seq = seq;
+    let entry = OutboxEntry {
+        counter: None,
+        envelope: Some(envelope),
+        callback: delivery.callback,
+        attempts: 0,
+        last_error: None,
+        batched: Some(delivery.batched),
+        cycles: Some(delivery.cycles),
+    };
+    outbox.insert((subscriber, seq), entry);
+    seq
+}
+
+// gives the message the next topic_seq of its topic, in the log it has it as seq too
+// the log keeps the last MAX_LOG_LENGTH messages of the topic
+fn append_to_log(
+    log: &mut LogStore,
+    topic_sequences: &mut TopicSequenceStore,
+    envelope: &mut Envelope,
+) {
+    let seq = topic_sequences.get(&envelope.topic).unwrap_or(0) + 1;
+    topic_sequences.insert(envelope.topic.clone(), seq);
+    envelope.topic_seq = Some(seq);
+    let mut logged = envelope.clone();
+    logged.seq = seq;
+    let key = LogKey {
+        topic: envelope.topic.clone(),
+        seq,
+    };
+    log.insert(key, StoredEnvelope(logged));
+    if seq > MAX_LOG_LENGTH {
+        log.remove(&LogKey {
+            topic: envelope.topic.clone(),
+            seq: seq - MAX_LOG_LENGTH,
+        });
+    }
+}
+
+// the logged messages of the topic after seq, oldest first
+fn log_range(log: &LogStore, topic: &str, after: u64, limit: usize) -> Vec<Envelope> {
+    let Some(start) = after.checked_add(1) else {
+        return Vec::new();
+    };
+    let start = LogKey {
+        topic: topic.to_string(),
+        seq: start,
+    };
+    log.range(start..)
+        .take_while(|(key, _)| key.topic == topic)
+        .take(limit)
+        .map(|(_, envelope)| envelope.0)
+        .collect()
+}
+
+// the oldest message of the subscriber, with the ones after it when they are batched for the same callback
+fn next_in_outbox(outbox: &Outbox, subscriber: Principal) -> Vec<(u64, OutboxEntry)> {
+    let mut entries = outbox
+        .range((subscriber, 0)..=(subscriber, u64::MAX))
+        .map(|((_, seq), entry)| (seq, entry));
+    let Some((seq, first)) = entries.next() else {
+        return Vec::new();
+    };
+    if !is_batched(&first) {
+        return vec![(seq, first)];
+    }
+    let callback = first.callback.clone();
+    let mut batch = vec![(seq, first)];
+    batch.extend(
+        entries
+            .take_while(|(_, entry)| is_batched(entry) && entry.callback == callback)
+            .take(MAX_BATCH_SIZE - 1),
+    );
+    batch
+}
+
+fn is_batched(entry: &OutboxEntry) -> bool {
+    entry.batched == Some(true) && entry.envelope.is_some() && entry.callback.is_some()
+}
+
+// adds one call to the usage of the topics in it, the call fee is split between its messages
+fn estimate_usage(usage: &mut UsageStore, batch: &[(u64, OutboxEntry)]) {
+    let call_fee = CALL_FEE / batch.len() as u128;
+    for (_, entry) in batch {
+        let (topic, bytes) = match (&entry.envelope, &entry.counter) {
+            (Some(envelope), _) => (&envelope.topic, Encode!(envelope).map_or(0, |b| b.len())),
+            (None, Some(counter)) => (&counter.topic, Encode!(counter).map_or(0, |b| b.len())),
+            (None, None) => continue,
+        };
+        let mut topic_usage = usage.get(topic).unwrap_or_default();
+        topic_usage.sends += 1;
+        topic_usage.bytes += bytes as u64;
+        topic_usage.estimated_cycles += call_fee + bytes as u128 * BYTE_FEE;
+        usage.insert(topic.clone(), topic_usage);
+    }
+}
+
+fn backoff(attempts: u32) -> Duration {
+    Duration::from_secs(RETRY_BASE_SECONDS << attempts.min(16))
+}
+
+fn move_to_dead_letters(
+    outbox: &mut Outbox,
+    dead_letters: &mut DeadLetterStore,
+    key: (Principal, u64),
+    entry: OutboxEntry,
+    error: String,
+    now: u64,
+) {
+    let dead_letter = DeadLetter {
+        counter: entry.counter,
+        envelope: entry.envelope,
+        callback: entry.callback,
+        attempts: entry.attempts,
+        last_error: error,
+        failed_at: now,
+        batched: entry.batched,
+        cycles: entry.cycles,
+    };
+    outbox.remove(&key);
+    dead_letters.insert(key, dead_letter);
+}
+
+// counts a failed call, after MAX_DELIVERY_ATTEMPTS the messages move to the dead letters
+// returns the wait before the next try while the messages stay in the outbox
+// the messages of a batch are retried together, so they all get the attempts of the first
+fn record_failure(
+    outbox: &mut Outbox,
+    dead_letters: &mut DeadLetterStore,
+    subscriber: Principal,
+    batch: Vec<(u64, OutboxEntry)>,
+    error: String,
+    now: u64,
+) -> Option<Duration> {
+    let attempts = batch.first().map_or(0, |(_, entry)| entry.attempts) + 1;
+    let given_up = attempts >= MAX_DELIVERY_ATTEMPTS;
+    for (seq, mut entry) in batch {
+        entry.attempts = attempts;
+        if given_up {
+            move_to_dead_letters(
+                outbox,
+                dead_letters,
+                (subscriber, seq),
+                entry,
+                error.clone(),
+                now,
+            );
+        } else {
+            entry.last_error = Some(error.clone());
+            outbox.insert((subscriber, seq), entry);
+        }
+    }
+    (!given_up).then(|| backoff(attempts))
+}
+
+// the envelope goes to the subscription's callback, the old "update_count" gets the counter and seq
+// a batch goes to the callback as a vec of envelopes, the cycles of all its messages are attached
+async fn send(
+    subscriber: Principal,
+    batch: &[(u64, OutboxEntry)],
+) -> Result<(), (RejectionCode, String)> {
+    let cycles = batch
+        .iter()
+        .map(|(_, entry)| entry.cycles.unwrap_or(0))
+        .sum();
+    let (seq, entry) = &batch[0];
+    if let (true, Some(callback)) = (is_batched(entry), &entry.callback) {
+        let envelopes: Vec<&Envelope> = batch
+            .iter()
+            .filter_map(|(_, entry)| entry.envelope.as_ref())
+            .collect();
+        return call::call_with_payment128(subscriber, callback, (envelopes,), cycles).await;
+    }
+    if let (Some(envelope), Some(callback)) = (&entry.envelope, &entry.callback) {
+        return call::call_with_payment128(subscriber, callback, (envelope,), cycles).await;
+    }
+    match entry.counter() {
+        Some(counter) => {
+            call::call_with_payment128(subscriber, "update_count", (counter, Some(*seq)), cycles)
+                .await
+        }
+        // publish never queues a Candid payload without a callback, there is nothing to send
+        None => Ok(()),
+    }
+}
+
+// sends the outbox of one subscriber in order, a failed call stops the loop until the retry timer fires
+async fn deliver(subscriber: Principal) {
+    if !DELIVERING.with(|d| d.borrow_mut().insert(subscriber)) {
+        return;
+    }
+    loop {
+        let batch = OUTBOX.with(|outbox| next_in_outbox(&outbox.borrow(), subscriber));
+        if batch.is_empty() {
+            break;
+        }
+        USAGE.with(|usage| estimate_usage(&mut usage.borrow_mut(), &batch));
+        let Err((code, message)) = send(subscriber, &batch).await else {
+            OUTBOX.with(|outbox| {
+                let mut outbox = outbox.borrow_mut();
+                for (seq, _) in &batch {
+                    outbox.remove(&(subscriber, *seq));
+                }
+            });
+            FAILURES.with(|f| f.borrow_mut().remove(&subscriber));
+            continue;
+        };
+        let error = format!("{:?}: {}", code, message);
+        let retry = OUTBOX.with(|outbox| {
+            DEAD_LETTERS.with(|dead_letters| {
+                record_failure(
+                    &mut outbox.borrow_mut(),
+                    &mut dead_letters.borrow_mut(),
+                    subscriber,
+                    batch,
+                    error,
+                    ic_cdk::api::time(),
+                )
+            })
+        });
+        let Some(delay) = retry else {
+            let failures = FAILURES.with(|f| {
+                let mut failures = f.borrow_mut();
+                let count = failures.get(&subscriber).unwrap_or(0) + 1;
+                failures.insert(subscriber, count);
+                count
+            });
+            if failures >= MAX_DEAD_LETTERS_IN_A_ROW {
+                drop_subscriber(subscriber);
+            }
+            continue;
+        };
+        // the subscriber stays in DELIVERING so new messages don't skip the wait
+        ic_cdk_timers::set_timer(delay, move || {
+            DELIVERING.with(|d| d.borrow_mut().remove(&subscriber));
+            ic_cdk::spawn(deliver(subscriber));
+        });
+        return;
+    }
+    DELIVERING.with(|d| d.borrow_mut().remove(&subscriber));
+}
+
+// the subscriber keeps failing, it loses every subscription and what is left in its outbox becomes dead letters
+// redeliver_dead_letters still works once it subscribed again
+fn drop_subscriber(subscriber: Principal) {
+    let topics = SUBSCRIBERS.with(|subscribers| subscribers.borrow().get(&subscriber));
+    for topic in topics
+        .map(|subscriptions| subscriptions.topics)
+        .unwrap_or_default()
+    {
+        remove_subscription(subscriber, topic);
+    }
+    let now = ic_cdk::api::time();
+    OUTBOX.with(|outbox| {
+        DEAD_LETTERS.with(|dead_letters| {
+            let mut outbox = outbox.borrow_mut();
+            let pending: Vec<(u64, OutboxEntry)> = outbox
+                .range((subscriber, 0)..=(subscriber, u64::MAX))
+                .map(|((_, seq), entry)| (seq, entry))
+                .collect();
+            for (seq, entry) in pending {
+                move_to_dead_letters(
+                    &mut outbox,
+                    &mut dead_letters.borrow_mut(),
+                    (subscriber, seq),
+                    entry,
+                    "subscriber was dropped".to_string(),
+                    now,
+                );
+            }
+        })
+    });
+    FAILURES.with(|f| f.borrow_mut().remove(&subscriber));
+}
+
+fn remove_subscription(subscriber: Principal, topic: String) {
+    SUBSCRIBERS.with(|subscribers| {
+        let mut subscribers = subscribers.borrow_mut();
+        let Some(mut subscriptions) = subscribers.get(&subscriber) else {
+            return;
+        };
+        subscriptions.topics.remove(&topic);
+        if subscriptions.topics.is_empty() {
+            subscribers.remove(&subscriber);
+        } else {
+            subscribers.insert(subscriber, subscriptions);
+        }
+    });
+    let index = if is_pattern(&topic) {
+        &PATTERN_INDEX
+    } else {
+        &TOPIC_INDEX
+    };
+    let key = TopicKey { topic, subscriber };
+    index.with(|topic_index| topic_index.borrow_mut().remove(&key));
+    FILTERS.with(|filters| filters.borrow_mut().remove(&key));
+    CALLBACKS.with(|callbacks| callbacks.borrow_mut().remove(&key));
+    EXPIRIES.with(|expiries| expiries.borrow_mut().remove(&key));
+    BATCH_WINDOWS.with(|windows| windows.borrow_mut().remove(&key));
+}
+
+fn is_expired(key: &TopicKey, now: u64) -> bool {
+    EXPIRIES
+        .with(|expiries| expiries.borrow().get(key))
+        .is_some_and(|expires_at| expires_at <= now)
+}
+
+fn remove_expired_subscriptions() {
+    let now = ic_cdk::api::time();
+    let expired: Vec<TopicKey> = EXPIRIES.with(|expiries| {
+        expiries
+            .borrow()
+            .iter()
+            .filter(|(_, expires_at)| *expires_at <= now)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    for key in expired {
+        remove_subscription(key.subscriber, key.topic);
+    }
+}
 
 #[update]
 //subscribe allows for the publisher canister to make a call to the subscriber canister and subscribe to topics.
-fn subscribe(subscriber: Subscriber) {
-        // Get the principal ID of the caller
+//subscribing again to the same topic replaces its filter, callback and ttl, that is also how it is renewed
+fn subscribe(subscriber: Subscriber) -> Result<SubscriptionStatus, SubscribeError> {
+    if !validate_topic(&subscriber.topic) {
+        return Err(SubscribeError::InvalidTopic);
+    }
+    if subscriber
+        .callback
+        .as_ref()
+        .is_some_and(|callback| callback.is_empty() || callback.len() > MAX_CALLBACK_LENGTH)
+    {
+        return Err(SubscribeError::InvalidCallback);
+    }
+    if subscriber.batch_seconds.is_some_and(|seconds| {
+        seconds == 0 || seconds > MAX_BATCH_SECONDS || subscriber.callback.is_none()
+    }) {
+        return Err(SubscribeError::InvalidBatch);
+    }
+    if subscriber.from_seq.is_some() && is_pattern(&subscriber.topic) {
+        return Err(SubscribeError::InvalidReplay);
+    }
+    // Get the principal ID of the caller
     let subscriber_principal_id = ic_cdk::caller();
-    // Insert the subscriber into the SUBSCRIBERS store
-    SUBSCRIBERS.with(|subscribers| {
+    // wildcard subscriptions are only checked when a message is sent
+    if !may_subscribe(
+        topic_acl(&subscriber.topic).as_ref(),
+        &subscriber_principal_id,
+    ) {
+        return Err(SubscribeError::NotAllowed);
+    }
+    let key = TopicKey {
+        topic: subscriber.topic.clone(),
+        subscriber: subscriber_principal_id,
+    };
+    let renewed = SUBSCRIBERS.with(|subscribers| {
         subscribers
+            .borrow()
+            .get(&subscriber_principal_id)
+            .is_some_and(|subscriptions| subscriptions.topics.contains(&subscriber.topic))
+    });
+    let expires_at = subscriber
+        .ttl_seconds
+        .map(|ttl| ic_cdk::api::time().saturating_add(ttl.saturating_mul(1_000_000_000)));
+    EXPIRIES.with(|expiries| match expires_at {
+        Some(expires_at) => expiries.borrow_mut().insert(key.clone(), expires_at),
+        None => expiries.borrow_mut().remove(&key),
+    });
+    BATCH_WINDOWS.with(|windows| match subscriber.batch_seconds {
+        Some(seconds) => windows.borrow_mut().insert(key.clone(), seconds),
+        None => windows.borrow_mut().remove(&key),
+    });
+    FILTERS.with(|filters| match subscriber.filter {
+        Some(filter) => filters
             .borrow_mut()
-            .insert(subscriber_principal_id, subscriber)
+            .insert(key.clone(), StoredFilter(filter)),
+        None => filters.borrow_mut().remove(&key),
+    });
+    CALLBACKS.with(|callbacks| match subscriber.callback {
+        Some(callback) => callbacks.borrow_mut().insert(key.clone(), callback),
+        None => callbacks.borrow_mut().remove(&key),
     });
+    let index = if is_pattern(&subscriber.topic) {
+        &PATTERN_INDEX
+    } else {
+        &TOPIC_INDEX
+    };
+    // Add the topic to the ones the caller already has
+    SUBSCRIBERS.with(|subscribers| {
+        index.with(|topic_index| {
+            add_subscription(
+                &mut subscribers.borrow_mut(),
+                &mut topic_index.borrow_mut(),
+                subscriber_principal_id,
+                subscriber.topic.clone(),
+            )
+        })
+    });
+    let replayed = subscriber
+        .from_seq
+        .map_or(0, |from_seq| replay(&key, from_seq));
+    Ok(SubscriptionStatus {
+        topic: subscriber.topic,
+        expires_at,
+        renewed,
+        replayed,
+    })
 }
-// Define an async function that allows the publisher canister to publish information into a topic in the subscribers canister.
 
+// queues the logged messages after from_seq for the subscription, the way fan_out would have
+// subscribe runs in one go, so they are in the outbox before anything published later
+fn replay(key: &TopicKey, from_seq: u64) -> u64 {
+    let filter = FILTERS.with(|filters| filters.borrow().get(key));
+    let callback = CALLBACKS.with(|callbacks| callbacks.borrow().get(key));
+    let window = BATCH_WINDOWS.with(|windows| windows.borrow().get(key));
+    let envelopes: Vec<Envelope> = LOG
+        .with(|log| log_range(&log.borrow(), &key.topic, from_seq, MAX_LOG_LENGTH as usize))
+        .into_iter()
+        .filter(|envelope| {
+            filter
+                .as_ref()
+                .is_none_or(|filter| filter.0.accepts(&envelope.payload))
+        })
+        .filter(|envelope| callback.is_some() || matches!(envelope.payload, Payload::Counter(_)))
+        .collect();
+    if envelopes.is_empty() {
+        return 0;
+    }
+    let replayed = envelopes.len() as u64;
+    OUTBOX.with(|outbox| {
+        SEQUENCES.with(|sequences| {
+            for envelope in envelopes {
+                let delivery = Delivery {
+                    callback: callback.clone(),
+                    batched: window.is_some(),
+                    cycles: 0,
+                };
+                enqueue(
+                    &mut outbox.borrow_mut(),
+                    &mut sequences.borrow_mut(),
+                    key.subscriber,
+                    envelope,
+                    delivery,
+                );
+            }
+        })
+    });
+    match window {
+        None => ic_cdk::spawn(deliver(key.subscriber)),
+        Some(seconds) => flush_after(key.subscriber, seconds),
+    }
+    replayed
+}
+
+// removes one topic of the caller, the other topics stay subscribed
 #[update]
-async fn publish(counter: Counter) {
-        // Iterate over the subscribers and notify those subscribed to the topic
+fn unsubscribe(topic: String) {
+    // nobody can be subscribed to a topic that doesn't fit in the index
+    if topic.len() > MAX_TOPIC_LENGTH {
+        return;
+    }
+    remove_subscription(ic_cdk::caller(), topic);
+}
+
+// the topics the caller is subscribed to
+#[query]
+fn list_subscriptions() -> Vec<String> {
     SUBSCRIBERS.with(|subscribers| {
-        for (k, v) in subscribers.borrow().iter() {
-            if v.topic == counter.topic {
-                    // Notify the subscriber by calling their "update_count" method with the counter
-                let _call_result: Result<(), _> =
-                    ic_cdk::notify(*k, "update_count", (&counter,));
+        subscribers
+            .borrow()
+            .get(&ic_cdk::caller())
+            .map(|subscriptions| subscriptions.topics.into_iter().collect())
+            .unwrap_or_default()
+    })
+}
+// Define an async function that allows the publisher canister to publish information into a topic in the subscribers canister.
+// cycles attached to the call are shared by the subscribers that get the message, see fan_out
+
+#[update]
+async fn publish(counter: Counter) -> Result<(), PublishError> {
+    check_publisher(&counter.topic)?;
+    fan_out(counter.topic, Payload::Counter(counter.value));
+    Ok(())
+}
+
+// publishes any payload, subscribers without a callback only get Counter payloads
+#[update]
+fn publish_message(topic: String, payload: Payload) -> Result<(), PublishError> {
+    check_publisher(&topic)?;
+    if let Payload::Candid(bytes) = &payload {
+        if bytes.len() > MAX_PAYLOAD_SIZE {
+            return Err(PublishError::PayloadTooLarge);
+        }
+    }
+    fan_out(topic, payload);
+    Ok(())
+}
+
+// queues the message for every matching subscription and starts the deliveries
+// batched subscriptions wait for the end of their window, any other message of the subscriber sends them sooner
+// attached cycles are split evenly and go along with the delivery calls, what doesn't split is refunded
+fn fan_out(topic: String, payload: Payload) {
+    // Find the subscribers of the topic, the index has them next to each other
+    let mut keys: Vec<TopicKey> = TOPIC_INDEX.with(|topic_index| {
+        topic_index
+            .borrow()
+            .range(topic_start(&topic)..)
+            .take_while(|(key, _)| key.topic == topic)
+            .map(|(key, _)| key)
+            .collect()
+    });
+    PATTERN_INDEX.with(|pattern_index| {
+        keys.extend(
+            pattern_index
+                .borrow()
+                .iter()
+                .filter(|(key, _)| topic_matches(&key.topic, &topic))
+                .map(|(key, _)| key),
+        )
+    });
+    // A subscriber matching through several subscriptions with the same callback still gets the message once
+    // with the shortest batch window of them, or none if one of them isn't batched
+    let acl = topic_acl(&topic);
+    let now = ic_cdk::api::time();
+    let mut targets: BTreeMap<(Principal, Option<String>), Option<u64>> = BTreeMap::new();
+    for key in keys
+        .into_iter()
+        .filter(|key| !is_expired(key, now))
+        .filter(|key| may_subscribe(acl.as_ref(), &key.subscriber))
+        .filter(|key| {
+            FILTERS
+                .with(|filters| filters.borrow().get(key))
+                .is_none_or(|filter| filter.0.accepts(&payload))
+        })
+    {
+        let callback = CALLBACKS.with(|callbacks| callbacks.borrow().get(&key));
+        if callback.is_none() && !matches!(payload, Payload::Counter(_)) {
+            continue;
+        }
+        let window = BATCH_WINDOWS.with(|windows| windows.borrow().get(&key));
+        targets
+            .entry((key.subscriber, callback))
+            .and_modify(|current| *current = current.zip(window).map(|(a, b)| a.min(b)))
+            .or_insert(window);
+    }
+    let cycles = match targets.len() as u128 {
+        0 => 0,
+        count => {
+            let share = call::msg_cycles_available128() / count;
+            call::msg_cycles_accept128(share * count);
+            share
+        }
+    };
+    USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        let mut topic_usage = usage.get(&topic).unwrap_or_default();
+        topic_usage.messages += targets.len() as u64;
+        topic_usage.cycles_attached += cycles * targets.len() as u128;
+        usage.insert(topic.clone(), topic_usage);
+    });
+    let mut envelope = Envelope {
+        topic,
+        seq: 0,
+        timestamp: ic_cdk::api::time(),
+        publisher: ic_cdk::caller(),
+        payload,
+        topic_seq: None,
+    };
+    // topics too long for a subscription aren't logged either, only a '**' subscription gets them
+    if envelope.topic.len() <= MAX_TOPIC_LENGTH {
+        LOG.with(|log| {
+            TOPIC_SEQUENCES.with(|topic_sequences| {
+                append_to_log(
+                    &mut log.borrow_mut(),
+                    &mut topic_sequences.borrow_mut(),
+                    &mut envelope,
+                )
+            })
+        });
+    }
+    // the shortest window of each subscriber, None when something has to go out now
+    let mut windows: BTreeMap<Principal, Option<u64>> = BTreeMap::new();
+    for ((subscriber, callback), window) in targets {
+        let delivery = Delivery {
+            callback,
+            batched: window.is_some(),
+            cycles,
+        };
+        // The message waits in the outbox until the subscriber's callback accepted it
+        OUTBOX.with(|outbox| {
+            SEQUENCES.with(|sequences| {
+                enqueue(
+                    &mut outbox.borrow_mut(),
+                    &mut sequences.borrow_mut(),
+                    subscriber,
+                    envelope.clone(),
+                    delivery,
+                )
+            })
+        });
+        windows
+            .entry(subscriber)
+            .and_modify(|current| *current = current.zip(window).map(|(a, b)| a.min(b)))
+            .or_insert(window);
+    }
+    for (subscriber, window) in windows {
+        match window {
+            None => ic_cdk::spawn(deliver(subscriber)),
+            Some(seconds) => flush_after(subscriber, seconds),
+        }
+    }
+}
+
+// delivers the batched messages of the subscriber at the end of the window, unless a timer is already set
+fn flush_after(subscriber: Principal, seconds: u64) {
+    if !FLUSHING.with(|f| f.borrow_mut().insert(subscriber)) {
+        return;
+    }
+    ic_cdk_timers::set_timer(Duration::from_secs(seconds), move || {
+        FLUSHING.with(|f| f.borrow_mut().remove(&subscriber));
+        ic_cdk::spawn(deliver(subscriber));
+    });
+}
+
+#[update]
+fn add_publisher(publisher: Principal) {
+    check_controller();
+    PUBLISHERS.with(|p| p.borrow_mut().insert(publisher, ()));
+}
+
+#[update]
+fn remove_publisher(publisher: Principal) {
+    check_controller();
+    PUBLISHERS.with(|p| p.borrow_mut().remove(&publisher));
+}
+
+#[query]
+fn list_publishers() -> Vec<Principal> {
+    PUBLISHERS.with(|p| p.borrow().iter().map(|(publisher, _)| publisher).collect())
+}
+
+// replaces the rules of the topic, existing subscriptions that are no longer allowed just stop getting messages
+#[update]
+fn set_topic_acl(topic: String, acl: TopicAcl) {
+    check_controller();
+    if topic.len() > MAX_TOPIC_LENGTH {
+        ic_cdk::trap("topic is too long");
+    }
+    ACLS.with(|acls| acls.borrow_mut().insert(topic, acl));
+}
+
+#[update]
+fn remove_topic_acl(topic: String) {
+    check_controller();
+    ACLS.with(|acls| acls.borrow_mut().remove(&topic));
+}
+
+#[query]
+fn get_topic_acl(topic: String) -> Option<TopicAcl> {
+    topic_acl(&topic)
+}
+
+// the logged messages of the topic after seq, at most limit (MAX_FETCH_PAGE at most)
+// ask again from the last topic_seq for more, only who may subscribe to the topic can read it
+#[query]
+fn fetch_since(topic: String, seq: u64, limit: Option<u32>) -> LogPage {
+    if !may_subscribe(topic_acl(&topic).as_ref(), &ic_cdk::caller()) {
+        ic_cdk::trap("caller is not allowed to subscribe to this topic");
+    }
+    let limit = limit.map_or(MAX_FETCH_PAGE, |l| (l as usize).min(MAX_FETCH_PAGE));
+    LOG.with(|log| {
+        let log = log.borrow();
+        LogPage {
+            messages: log_range(&log, &topic, seq, limit),
+            first_seq: log_range(&log, &topic, 0, 1)
+                .first()
+                .and_then(|envelope| envelope.topic_seq),
+            last_seq: TOPIC_SEQUENCES.with(|t| t.borrow().get(&topic).unwrap_or(0)),
+        }
+    })
+}
+
+#[query]
+fn get_topic_usage(topic: String) -> Option<TopicUsage> {
+    USAGE.with(|usage| usage.borrow().get(&topic))
+}
+
+#[query]
+fn list_topic_usage() -> Vec<(String, TopicUsage)> {
+    USAGE.with(|usage| usage.borrow().iter().collect())
+}
+
+// messages given up on for one subscriber, with their sequence numbers
+#[query]
+fn get_dead_letters(subscriber: Principal) -> Vec<(u64, DeadLetter)> {
+    DEAD_LETTERS.with(|d| {
+        d.borrow()
+            .range((subscriber, 0)..=(subscriber, u64::MAX))
+            .map(|((_, seq), dead_letter)| (seq, dead_letter))
+            .collect()
+    })
+}
+
+// number of messages still waiting for the subscriber
+#[query]
+fn get_pending_count(subscriber: Principal) -> u64 {
+    OUTBOX.with(|outbox| {
+        outbox
+            .borrow()
+            .range((subscriber, 0)..=(subscriber, u64::MAX))
+            .count() as u64
+    })
+}
+
+// puts the dead letters of the subscriber back in the outbox, once the subscriber was fixed
+// they get new sequence numbers, the subscriber already saw higher ones than the old
+#[update]
+fn redeliver_dead_letters(subscriber: Principal) {
+    check_controller();
+    let dead_letters = get_dead_letters(subscriber);
+    for (seq, dead_letter) in dead_letters {
+        DEAD_LETTERS.with(|d| d.borrow_mut().remove(&(subscriber, seq)));
+        // letters from before envelopes only have a counter, they keep going to "update_count"
+        let envelope = match (dead_letter.envelope, dead_letter.counter) {
+            (Some(envelope), _) => envelope,
+            (None, Some(counter)) => Envelope {
+                topic: counter.topic,
+                seq: 0,
+                timestamp: dead_letter.failed_at,
+                publisher: ic_cdk::id(),
+                payload: Payload::Counter(counter.value),
+                topic_seq: None,
+            },
+            (None, None) => continue,
+        };
+        OUTBOX.with(|outbox| {
+            SEQUENCES.with(|sequences| {
+                enqueue(
+                    &mut outbox.borrow_mut(),
+                    &mut sequences.borrow_mut(),
+                    subscriber,
+                    envelope,
+                    Delivery {
+                        callback: dead_letter.callback,
+                        batched: dead_letter.batched == Some(true),
+                        cycles: dead_letter.cycles.unwrap_or(0),
+                    },
+                )
+            })
+        });
+    }
+    ic_cdk::spawn(deliver(subscriber));
+}
+
+#[init]
+fn init() {
+    ic_cdk_timers::set_timer_interval(
+        Duration::from_secs(EXPIRY_SWEEP_SECONDS),
+        remove_expired_subscriptions,
+    );
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    init();
+    // timers don't survive the upgrade, start over for everyone with messages left
+    let waiting: BTreeSet<Principal> = OUTBOX.with(|outbox| {
+        outbox
+            .borrow()
+            .iter()
+            .map(|((subscriber, _), _)| subscriber)
+            .collect()
+    });
+    for subscriber in waiting {
+        ic_cdk_timers::set_timer(Duration::ZERO, move || ic_cdk::spawn(deliver(subscriber)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a counter message as publish builds it, enqueue sets the sequence number
+    fn envelope(topic: &str) -> Envelope {
+        Envelope {
+            topic: topic.to_string(),
+            seq: 0,
+            timestamp: 0,
+            publisher: Principal::anonymous(),
+            payload: Payload::Counter(1),
+            topic_seq: None,
+        }
+    }
+
+    #[test]
+    fn subscriptions_survive_upgrade() {
+        // DefaultMemoryImpl is a shared vector outside of wasm, the clone stands in for the stable memory
+        let stable_memory = DefaultMemoryImpl::default();
+        let principal = Principal::from_slice(&[1]);
+        {
+            let memory_manager = MemoryManager::init(stable_memory.clone());
+            let mut subscribers = init_subscribers(&memory_manager);
+            let mut topic_index = init_topic_index(&memory_manager);
+            add_subscription(
+                &mut subscribers,
+                &mut topic_index,
+                principal,
+                "count".to_string(),
+            );
+            add_subscription(
+                &mut subscribers,
+                &mut topic_index,
+                principal,
+                "other".to_string(),
+            );
+        }
+        // everything on the heap is gone after the upgrade, the canister starts again from the stable memory
+        let memory_manager = MemoryManager::init(stable_memory);
+        let subscribers = init_subscribers(&memory_manager);
+        let topic_index = init_topic_index(&memory_manager);
+        assert_eq!(subscribers.len(), 1);
+        let topics: Vec<String> = subscribers
+            .get(&principal)
+            .unwrap()
+            .topics
+            .into_iter()
+            .collect();
+        assert_eq!(topics, ["count", "other"]);
+        assert!(topic_index.contains_key(&TopicKey {
+            topic: "count".to_string(),
+            subscriber: principal,
+        }));
+    }
+
+    #[test]
+    fn sequence_numbers_are_per_subscriber() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut outbox: Outbox = StableBTreeMap::init(memory_manager.get(OUTBOX_MEMORY_ID));
+        let mut sequences: SequenceStore =
+            StableBTreeMap::init(memory_manager.get(SEQUENCES_MEMORY_ID));
+        let first = Principal::from_slice(&[1]);
+        let second = Principal::from_slice(&[2]);
+        let envelope = envelope("count");
+        assert_eq!(
+            enqueue(
+                &mut outbox,
+                &mut sequences,
+                first,
+                envelope.clone(),
+                Delivery::default()
+            ),
+            1
+        );
+        assert_eq!(
+            enqueue(
+                &mut outbox,
+                &mut sequences,
+                first,
+                envelope.clone(),
+                Delivery::default()
+            ),
+            2
+        );
+        assert_eq!(
+            enqueue(
+                &mut outbox,
+                &mut sequences,
+                second,
+                envelope,
+                Delivery::default()
+            ),
+            1
+        );
+        let keys: Vec<(Principal, u64)> = outbox.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, [(first, 1), (first, 2), (second, 1)]);
+        // the envelope carries the sequence number the subscriber dedupes with
+        let entry = outbox.get(&(first, 2)).unwrap();
+        assert_eq!(entry.envelope.unwrap().seq, 2);
+    }
+
+    #[test]
+    fn failed_messages_back_off_then_become_dead_letters() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut outbox: Outbox = StableBTreeMap::init(memory_manager.get(OUTBOX_MEMORY_ID));
+        let mut sequences: SequenceStore =
+            StableBTreeMap::init(memory_manager.get(SEQUENCES_MEMORY_ID));
+        let mut dead_letters: DeadLetterStore =
+            StableBTreeMap::init(memory_manager.get(DEAD_LETTERS_MEMORY_ID));
+        let subscriber = Principal::from_slice(&[1]);
+        let batched = Delivery {
+            callback: Some("on_messages".to_string()),
+            batched: true,
+            cycles: 0,
+        };
+        for _ in 0..2 {
+            enqueue(
+                &mut outbox,
+                &mut sequences,
+                subscriber,
+                envelope("count"),
+                batched.clone(),
+            );
+        }
+        let mut fail = |outbox: &mut Outbox| {
+            let batch = next_in_outbox(outbox, subscriber);
+            record_failure(
+                outbox,
+                &mut dead_letters,
+                subscriber,
+                batch,
+                "rejected".to_string(),
+                7,
+            )
+        };
+        // the wait doubles with every failure while the batch stays in the outbox
+        for (attempts, seconds) in [(1, 4), (2, 8), (3, 16), (4, 32)] {
+            assert_eq!(fail(&mut outbox), Some(Duration::from_secs(seconds)));
+            for (_, entry) in next_in_outbox(&outbox, subscriber) {
+                assert_eq!(entry.attempts, attempts);
             }
         }
-    });    
+        assert_eq!(fail(&mut outbox), None);
+        assert!(outbox.is_empty());
+        let dead: Vec<(Principal, u64)> = dead_letters.iter().map(|(key, _)| key).collect();
+        assert_eq!(dead, [(subscriber, 1), (subscriber, 2)]);
+        let dead_letter = dead_letters.get(&(subscriber, 2)).unwrap();
+        assert_eq!(dead_letter.attempts, MAX_DELIVERY_ATTEMPTS);
+        assert_eq!(dead_letter.last_error, "rejected");
+        assert_eq!(dead_letter.failed_at, 7);
+        assert_eq!(dead_letter.batched, Some(true));
+    }
+
+    #[test]
+    fn wildcards_match_levels() {
+        assert!(topic_matches("scores/math/*", "scores/math/algebra"));
+        assert!(!topic_matches("scores/math/*", "scores/math"));
+        assert!(!topic_matches("scores/math/*", "scores/math/algebra/test"));
+        assert!(topic_matches("scores/*/algebra", "scores/math/algebra"));
+        assert!(topic_matches("scores/**", "scores/math/algebra"));
+        assert!(topic_matches("scores/**", "scores"));
+        assert!(!topic_matches("scores/**", "grades/math"));
+        assert!(validate_topic("scores/**"));
+        assert!(!validate_topic("scores/**/math"));
+    }
+
+    #[test]
+    fn topic_acls_restrict_listed_sides() {
+        let allowed = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        let acl = TopicAcl {
+            publishers: Some(BTreeSet::from([allowed])),
+            subscribers: None,
+        };
+        assert!(may_publish(Some(&acl), &allowed));
+        assert!(!may_publish(Some(&acl), &other));
+        assert!(may_subscribe(Some(&acl), &other));
+        assert!(may_publish(None, &other));
+    }
+
+    #[test]
+    fn publishers_need_the_allowlist_and_the_topic_acl() {
+        let publisher = Principal::from_slice(&[3]);
+        let other = Principal::from_slice(&[2]);
+        assert_eq!(
+            check_allowed_publisher(&publisher, "count"),
+            Err(PublishError::NotPublisher)
+        );
+        PUBLISHERS.with(|p| p.borrow_mut().insert(publisher, ()));
+        assert_eq!(check_allowed_publisher(&publisher, "count"), Ok(()));
+        let acl = TopicAcl {
+            publishers: Some(BTreeSet::from([other])),
+            subscribers: None,
+        };
+        ACLS.with(|acls| acls.borrow_mut().insert("count".to_string(), acl));
+        assert_eq!(
+            check_allowed_publisher(&publisher, "count"),
+            Err(PublishError::NotAllowed)
+        );
+        // the ACL is only for its own topic
+        assert_eq!(check_allowed_publisher(&publisher, "other"), Ok(()));
+        // being listed in the ACL is not enough without the allowlist
+        assert_eq!(
+            check_allowed_publisher(&other, "count"),
+            Err(PublishError::NotPublisher)
+        );
+    }
+
+    #[test]
+    fn removing_a_subscription_clears_its_settings() {
+        let subscriber = Principal::from_slice(&[4]);
+        let key = TopicKey {
+            topic: "count".to_string(),
+            subscriber,
+        };
+        SUBSCRIBERS.with(|subscribers| {
+            TOPIC_INDEX.with(|topic_index| {
+                add_subscription(
+                    &mut subscribers.borrow_mut(),
+                    &mut topic_index.borrow_mut(),
+                    subscriber,
+                    "count".to_string(),
+                )
+            })
+        });
+        CALLBACKS.with(|callbacks| {
+            callbacks
+                .borrow_mut()
+                .insert(key.clone(), "on_message".to_string())
+        });
+        EXPIRIES.with(|expiries| expiries.borrow_mut().insert(key.clone(), 100));
+        assert!(!is_expired(&key, 99));
+        assert!(is_expired(&key, 100));
+
+        remove_subscription(subscriber, "count".to_string());
+        assert!(!SUBSCRIBERS.with(|subscribers| subscribers.borrow().contains_key(&subscriber)));
+        assert!(!TOPIC_INDEX.with(|topic_index| topic_index.borrow().contains_key(&key)));
+        assert!(!CALLBACKS.with(|callbacks| callbacks.borrow().contains_key(&key)));
+        assert!(!is_expired(&key, u64::MAX));
+    }
+
+    #[test]
+    fn batched_messages_go_out_together() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut outbox: Outbox = StableBTreeMap::init(memory_manager.get(OUTBOX_MEMORY_ID));
+        let mut sequences: SequenceStore =
+            StableBTreeMap::init(memory_manager.get(SEQUENCES_MEMORY_ID));
+        let subscriber = Principal::from_slice(&[5]);
+        let envelope = envelope("count");
+        let batched = |callback: &str| Delivery {
+            callback: Some(callback.to_string()),
+            batched: true,
+            cycles: 10,
+        };
+        for delivery in [
+            batched("on_messages"),
+            batched("on_messages"),
+            batched("other"),
+            Delivery::default(),
+        ] {
+            enqueue(
+                &mut outbox,
+                &mut sequences,
+                subscriber,
+                envelope.clone(),
+                delivery,
+            );
+        }
+        let seqs = |batch: Vec<(u64, OutboxEntry)>| -> Vec<u64> {
+            batch.into_iter().map(|(seq, _)| seq).collect()
+        };
+        // a batch stops at the first message for another callback
+        assert_eq!(seqs(next_in_outbox(&outbox, subscriber)), [1, 2]);
+        outbox.remove(&(subscriber, 1));
+        outbox.remove(&(subscriber, 2));
+        assert_eq!(seqs(next_in_outbox(&outbox, subscriber)), [3]);
+        outbox.remove(&(subscriber, 3));
+        assert_eq!(seqs(next_in_outbox(&outbox, subscriber)), [4]);
+        outbox.remove(&(subscriber, 4));
+        assert!(next_in_outbox(&outbox, subscriber).is_empty());
+    }
+
+    #[test]
+    fn usage_estimate_splits_the_call_fee() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut outbox: Outbox = StableBTreeMap::init(memory_manager.get(OUTBOX_MEMORY_ID));
+        let mut sequences: SequenceStore =
+            StableBTreeMap::init(memory_manager.get(SEQUENCES_MEMORY_ID));
+        let mut usage: UsageStore = StableBTreeMap::init(memory_manager.get(USAGE_MEMORY_ID));
+        let subscriber = Principal::from_slice(&[6]);
+        for topic in ["a", "b"] {
+            enqueue(
+                &mut outbox,
+                &mut sequences,
+                subscriber,
+                envelope(topic),
+                Delivery::default(),
+            );
+        }
+        let batch: Vec<(u64, OutboxEntry)> = outbox
+            .iter()
+            .map(|((_, seq), entry)| (seq, entry))
+            .collect();
+        estimate_usage(&mut usage, &batch);
+        estimate_usage(&mut usage, &batch[..1]);
+        let a = usage.get(&"a".to_string()).unwrap();
+        let b = usage.get(&"b".to_string()).unwrap();
+        assert_eq!((a.sends, b.sends), (2, 1));
+        assert_eq!(a.bytes, 2 * b.bytes);
+        let bytes_fee = b.bytes as u128 * BYTE_FEE;
+        assert_eq!(b.estimated_cycles, CALL_FEE / 2 + bytes_fee);
+        assert_eq!(a.estimated_cycles, CALL_FEE / 2 + CALL_FEE + 2 * bytes_fee);
+    }
+
+    #[test]
+    fn log_keeps_the_last_messages_of_each_topic() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut log: LogStore = StableBTreeMap::init(memory_manager.get(LOG_MEMORY_ID));
+        let mut topic_sequences: TopicSequenceStore =
+            StableBTreeMap::init(memory_manager.get(TOPIC_SEQUENCES_MEMORY_ID));
+        for _ in 0..MAX_LOG_LENGTH + 5 {
+            append_to_log(&mut log, &mut topic_sequences, &mut envelope("a"));
+        }
+        let mut other = envelope("b");
+        append_to_log(&mut log, &mut topic_sequences, &mut other);
+        assert_eq!(other.topic_seq, Some(1));
+
+        let seqs = |messages: Vec<Envelope>| -> Vec<u64> {
+            messages
+                .iter()
+                .map(|envelope| envelope.topic_seq.unwrap())
+                .collect()
+        };
+        // the first five were dropped
+        assert_eq!(seqs(log_range(&log, "a", 0, 2)), [6, 7]);
+        assert_eq!(
+            log_range(&log, "a", 0, usize::MAX).len() as u64,
+            MAX_LOG_LENGTH
+        );
+        assert_eq!(
+            seqs(log_range(&log, "a", MAX_LOG_LENGTH + 3, 10)),
+            [MAX_LOG_LENGTH + 4, MAX_LOG_LENGTH + 5]
+        );
+        assert_eq!(seqs(log_range(&log, "b", 0, 10)), [1]);
+        assert!(log_range(&log, "a", u64::MAX, 10).is_empty());
+    }
 }
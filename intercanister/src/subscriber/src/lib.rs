@@ -1,46 +1,503 @@
-use candid::{CandidType, Principal};
+use candid::{CandidType, Decode, Deserialize, Encode, Principal};
 use ic_cdk::{query, update};
-use serde::Deserialize;
-use std::cell::Cell;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use messages::{
+    Counter, Envelope, Filter, Payload, SubscribeError, Subscriber, SubscriptionStatus,
+};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
 
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+// every message received, ordered by topic and then by time so a time range of one topic is one range
+type HistoryStore = StableBTreeMap<HistoryKey, Received, Memory>;
+
+const PUBLISHERS_MEMORY_ID: MemoryId = MemoryId::new(0);
+const LAST_SEQUENCE_MEMORY_ID: MemoryId = MemoryId::new(1);
+const TOPICS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const HISTORY_MEMORY_ID: MemoryId = MemoryId::new(3);
+const NEXT_ID_MEMORY_ID: MemoryId = MemoryId::new(4);
+const SUBSCRIPTIONS_MEMORY_ID: MemoryId = MemoryId::new(5);
+
+// most messages get_history returns at once
+const MAX_HISTORY_PAGE: usize = 100;
+// the history keeps this many of the latest messages of every topic
+const MAX_HISTORY_LENGTH: u64 = 1000;
 
 thread_local! {
-    static COUNTER: Cell<u64> = Cell::new(0);
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    // publishers set up with setup_subscribe, only they can deliver messages
+    // kept in stable memory so deliveries keep working after an upgrade
+    static PUBLISHERS: RefCell<StableBTreeMap<Principal, (), Memory>> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(PUBLISHERS_MEMORY_ID))));
+
+    // highest sequence number seen from each publisher, a retried message is counted only once
+    static LAST_SEQUENCE: RefCell<StableBTreeMap<Principal, u64, Memory>> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(LAST_SEQUENCE_MEMORY_ID))));
+
+    static TOPICS: RefCell<StableBTreeMap<String, TopicState, Memory>> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(TOPICS_MEMORY_ID))));
+
+    static HISTORY: RefCell<HistoryStore> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(HISTORY_MEMORY_ID))));
+
+    // id of the next history entry, it keeps two messages received in the same round apart
+    static NEXT_ID: RefCell<StableCell<u64, Memory>> = MEMORY_MANAGER.with(|m| {
+        RefCell::new(
+            StableCell::init(m.borrow().get(NEXT_ID_MEMORY_ID), 0)
+                .expect("failed to init the next history id"),
+        )
+    });
+
+    // topics set up with each publisher, a publisher stays registered while it has one
+    static SUBSCRIPTIONS: RefCell<StableBTreeMap<Principal, Topics, Memory>> =
+        MEMORY_MANAGER.with(|m| RefCell::new(StableBTreeMap::init(m.borrow().get(SUBSCRIPTIONS_MEMORY_ID))));
 }
 
-#[derive(Clone, Debug, CandidType, Deserialize)]
-struct Counter {
-    topic: String,
-    value: u64,
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct Topics {
+    topics: BTreeSet<String>,
 }
 
+// what this canister knows about one topic
+// count sums the Counter payloads, messages counts everything received
+// last_topic_seq is where setup_subscribe can start again from with from_seq
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct TopicState {
+    count: u64,
+    messages: u64,
+    last_received_at: u64,
+    last_topic_seq: Option<u64>,
+}
 
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, CandidType, Deserialize)]
+struct HistoryKey {
+    topic: String,
+    received_at: u64,
+    id: u64,
+}
+
+// one received message, seq and sent_at are missing for messages that came through update_count
 #[derive(Clone, Debug, CandidType, Deserialize)]
-struct Subscriber {
+struct Received {
     topic: String,
+    received_at: u64,
+    publisher: Principal,
+    seq: Option<u64>,
+    sent_at: Option<u64>,
+    payload: Payload,
+    topic_seq: Option<u64>,
+}
+
+impl Storable for TopicState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Topics {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for HistoryKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for Received {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Unbounded;
 }
 
 //provides functionality for the publisher canister to subscribe to topics within the subscriber canister. This function is called by the publisher canister.
+//the topic can use '*' and '**' levels, like "scores/math/*"
+//messages come back through on_message
+//only controllers can call it, it decides which canister may send messages here
+//with ttl_seconds the subscription has to be set up again before it expires, that renews it
+//with batch_seconds the publisher holds the messages that long and sends them together to on_messages
+//with from_seq the publisher first sends what it still has of the topic after it, e.g. the last_topic_seq of get_topic
+#[update]
+async fn setup_subscribe(
+    publisher_id: Principal,
+    topic: String,
+    filter: Option<Filter>,
+    ttl_seconds: Option<u64>,
+    batch_seconds: Option<u64>,
+    from_seq: Option<u64>,
+) -> Result<SubscriptionStatus, SubscribeError> {
+    check_controller();
+    // registered before the call, the publisher may deliver before it answers
+    let added = PUBLISHERS.with(|p| p.borrow_mut().insert(publisher_id, ()).is_none());
+    let subscriber = Subscriber {
+        topic: topic.clone(),
+        filter,
+        callback: Some(
+            if batch_seconds.is_some() {
+                "on_messages"
+            } else {
+                "on_message"
+            }
+            .to_string(),
+        ),
+        ttl_seconds,
+        batch_seconds,
+        from_seq,
+    };
+    let status = match ic_cdk::call(publisher_id, "subscribe", (subscriber,)).await {
+        Ok((status,)) => status,
+        Err((code, message)) => Err(SubscribeError::CallFailed(format!(
+            "{:?}: {}",
+            code, message
+        ))),
+    };
+    match status {
+        Ok(_) => {
+            SUBSCRIPTIONS.with(|s| {
+                let mut subscriptions = s.borrow_mut();
+                let mut topics = subscriptions.get(&publisher_id).unwrap_or_default();
+                topics.topics.insert(topic);
+                subscriptions.insert(publisher_id, topics);
+            });
+        }
+        Err(_) if added => {
+            PUBLISHERS.with(|p| p.borrow_mut().remove(&publisher_id));
+        }
+        Err(_) => {}
+    }
+    status
+}
+
+//ends a subscription made with setup_subscribe
+//once no topic is left with the publisher it can't send messages here anymore
 #[update]
-async fn setup_subscribe(publisher_id: Principal, topic: String) {
-    let subscriber = Subscriber { topic };
-    let _call_result: Result<(), _> =
-        ic_cdk::call(publisher_id, "subscribe", (subscriber,)).await;
+async fn teardown_subscribe(publisher_id: Principal, topic: String) -> Result<(), String> {
+    check_controller();
+    let _: () = ic_cdk::call(publisher_id, "unsubscribe", (topic.clone(),))
+        .await
+        .map_err(|(code, message)| format!("{:?}: {}", code, message))?;
+    SUBSCRIPTIONS.with(|s| {
+        let mut subscriptions = s.borrow_mut();
+        let mut topics = subscriptions.get(&publisher_id).unwrap_or_default();
+        topics.topics.remove(&topic);
+        if topics.topics.is_empty() {
+            subscriptions.remove(&publisher_id);
+            PUBLISHERS.with(|p| p.borrow_mut().remove(&publisher_id));
+        } else {
+            subscriptions.insert(publisher_id, topics);
+        }
+    });
+    Ok(())
+}
+
+fn check_controller() {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("only controllers can set up subscriptions");
+    }
+}
+
+fn check_publisher() {
+    let caller = ic_cdk::caller();
+    if !PUBLISHERS.with(|p| p.borrow().contains_key(&caller)) {
+        ic_cdk::trap("caller is not a registered publisher");
+    }
+}
+
+//the publisher delivers in order with a sequence number, anything not above the last one was already counted
+fn is_new(seq: u64) -> bool {
+    let publisher = ic_cdk::caller();
+    LAST_SEQUENCE.with(|l| {
+        let mut last = l.borrow_mut();
+        if last.get(&publisher).is_some_and(|last_seq| seq <= last_seq) {
+            return false;
+        }
+        last.insert(publisher, seq);
+        true
+    })
+}
+
+//updates the state of the topic and adds the message to the history
+fn record(message: Received) {
+    let messages = TOPICS.with(|t| {
+        let mut topics = t.borrow_mut();
+        let mut state = topics.get(&message.topic).unwrap_or_default();
+        if let Payload::Counter(value) = message.payload {
+            state.count = state.count.saturating_add(value);
+        }
+        state.messages += 1;
+        state.last_received_at = message.received_at;
+        if message.topic_seq.is_some() {
+            state.last_topic_seq = message.topic_seq;
+        }
+        let messages = state.messages;
+        topics.insert(message.topic.clone(), state);
+        messages
+    });
+    let id = NEXT_ID.with(|n| {
+        let mut next = n.borrow_mut();
+        let id = *next.get();
+        next.set(id + 1)
+            .expect("failed to save the next history id");
+        id
+    });
+    let key = HistoryKey {
+        topic: message.topic.clone(),
+        received_at: message.received_at,
+        id,
+    };
+    HISTORY.with(|h| add_to_history(&mut h.borrow_mut(), key, message, messages));
+}
+
+// messages is how many the topic has received with this one, past MAX_HISTORY_LENGTH the oldest one goes
+fn add_to_history(history: &mut HistoryStore, key: HistoryKey, message: Received, messages: u64) {
+    let start = HistoryKey {
+        topic: key.topic.clone(),
+        received_at: 0,
+        id: 0,
+    };
+    history.insert(key, message);
+    if messages <= MAX_HISTORY_LENGTH {
+        return;
+    }
+    let oldest = history
+        .range(start.clone()..)
+        .next()
+        .map(|(key, _)| key)
+        .filter(|key| key.topic == start.topic);
+    if let Some(oldest) = oldest {
+        history.remove(&oldest);
+    }
+}
+
+// messages of one topic received between from and to (both included), oldest first
+fn history_range(
+    history: &HistoryStore,
+    topic: &str,
+    from: u64,
+    to: u64,
+    limit: usize,
+) -> Vec<Received> {
+    let start = HistoryKey {
+        topic: topic.to_string(),
+        received_at: from,
+        id: 0,
+    };
+    let end = HistoryKey {
+        topic: topic.to_string(),
+        received_at: to,
+        id: u64::MAX,
+    };
+    if start > end {
+        return Vec::new();
+    }
+    history
+        .range(start..=end)
+        .take(limit)
+        .map(|(_, message)| message)
+        .collect()
 }
 
 //updates the counter record for each published value in a topic within the subscriber canister.
+//kept for subscriptions made before callbacks, they still get the counter here
 #[update]
-fn update_count(counter: Counter) {
-    COUNTER.with(|c| {
-        c.set(c.get() + counter.value);
+fn update_count(counter: Counter, seq: Option<u64>) {
+    check_publisher();
+    accept_cycles();
+    if seq.is_some_and(|seq| !is_new(seq)) {
+        return;
+    }
+    record(Received {
+        topic: counter.topic,
+        received_at: ic_cdk::api::time(),
+        publisher: ic_cdk::caller(),
+        seq,
+        sent_at: None,
+        payload: Payload::Counter(counter.value),
+        topic_seq: None,
     });
 }
 
+//receives every message of the topics set up with setup_subscribe
+//Candid payloads only count as messages, they are kept as they are in the history
+#[update]
+fn on_message(envelope: Envelope) {
+    check_publisher();
+    accept_cycles();
+    receive(envelope);
+}
+
+//receives the messages of batched subscriptions, in sequence order
+#[update]
+fn on_messages(envelopes: Vec<Envelope>) {
+    check_publisher();
+    accept_cycles();
+    for envelope in envelopes {
+        receive(envelope);
+    }
+}
+
+fn receive(envelope: Envelope) {
+    if !is_new(envelope.seq) {
+        return;
+    }
+    record(Received {
+        topic: envelope.topic,
+        received_at: ic_cdk::api::time(),
+        publisher: envelope.publisher,
+        seq: Some(envelope.seq),
+        sent_at: Some(envelope.timestamp),
+        payload: envelope.payload,
+        topic_seq: envelope.topic_seq,
+    });
+}
+
+//publishers can attach cycles to pay for the messages they send, they are kept
+fn accept_cycles() {
+    let available = ic_cdk::api::call::msg_cycles_available128();
+    if available > 0 {
+        ic_cdk::api::call::msg_cycles_accept128(available);
+    }
+}
+
+#[query]
+fn get_publishers() -> Vec<Principal> {
+    PUBLISHERS.with(|p| p.borrow().iter().map(|(publisher, _)| publisher).collect())
+}
+
+//topics set up with each publisher
+#[query]
+fn get_subscriptions() -> Vec<(Principal, Vec<String>)> {
+    SUBSCRIPTIONS.with(|s| {
+        s.borrow()
+            .iter()
+            .map(|(publisher, topics)| (publisher, topics.topics.into_iter().collect()))
+            .collect()
+    })
+}
 
 //allows the Counter value to be queried and returned in a call.
+//it is the sum over all topics, get_topic has the count of one
 #[query]
 fn get_count() -> u64 {
-    COUNTER.with(|c| {
-        c.get()
+    TOPICS.with(|t| {
+        t.borrow()
+            .iter()
+            .fold(0u64, |sum, (_, state)| sum.saturating_add(state.count))
+    })
+}
+
+#[query]
+fn get_topic(topic: String) -> Option<TopicState> {
+    TOPICS.with(|t| t.borrow().get(&topic))
+}
+
+#[query]
+fn list_topics() -> Vec<(String, TopicState)> {
+    TOPICS.with(|t| t.borrow().iter().collect())
+}
+
+//messages of the topic received in [from, to], missing bounds are open
+//a page has at most limit (MAX_HISTORY_PAGE at most) messages, ask again from the last received_at for more
+#[query]
+fn get_history(
+    topic: String,
+    from: Option<u64>,
+    to: Option<u64>,
+    limit: Option<u32>,
+) -> Vec<Received> {
+    let limit = limit.map_or(MAX_HISTORY_PAGE, |l| (l as usize).min(MAX_HISTORY_PAGE));
+    HISTORY.with(|h| {
+        history_range(
+            &h.borrow(),
+            &topic,
+            from.unwrap_or(0),
+            to.unwrap_or(u64::MAX),
+            limit,
+        )
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn received(topic: &str, received_at: u64) -> (HistoryKey, Received) {
+        let key = HistoryKey {
+            topic: topic.to_string(),
+            received_at,
+            id: received_at,
+        };
+        let message = Received {
+            topic: topic.to_string(),
+            received_at,
+            publisher: Principal::anonymous(),
+            seq: None,
+            sent_at: None,
+            payload: Payload::Counter(1),
+            topic_seq: None,
+        };
+        (key, message)
+    }
+
+    #[test]
+    fn history_is_ranged_by_topic_and_time() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut history: HistoryStore = StableBTreeMap::init(memory_manager.get(HISTORY_MEMORY_ID));
+        for (topic, received_at) in [("a", 10), ("a", 20), ("b", 15), ("a", 30)] {
+            let (key, message) = received(topic, received_at);
+            history.insert(key, message);
+        }
+        let times = |messages: Vec<Received>| -> Vec<u64> {
+            messages.iter().map(|message| message.received_at).collect()
+        };
+        assert_eq!(
+            times(history_range(&history, "a", 0, u64::MAX, 10)),
+            [10, 20, 30]
+        );
+        assert_eq!(times(history_range(&history, "a", 15, 30, 10)), [20, 30]);
+        assert_eq!(times(history_range(&history, "a", 0, u64::MAX, 1)), [10]);
+        assert_eq!(times(history_range(&history, "b", 0, u64::MAX, 10)), [15]);
+        assert!(history_range(&history, "a", 30, 10, 10).is_empty());
+    }
+
+    #[test]
+    fn history_keeps_the_last_messages_of_each_topic() {
+        let memory_manager = MemoryManager::init(DefaultMemoryImpl::default());
+        let mut history: HistoryStore = StableBTreeMap::init(memory_manager.get(HISTORY_MEMORY_ID));
+        let (key, message) = received("a", 0);
+        add_to_history(&mut history, key, message, 1);
+        for messages in 1..=MAX_HISTORY_LENGTH + 5 {
+            let (key, message) = received("b", messages);
+            add_to_history(&mut history, key, message, messages);
+        }
+        let times = |messages: Vec<Received>| -> Vec<u64> {
+            messages.iter().map(|message| message.received_at).collect()
+        };
+        // the first five were dropped, the other topic keeps its message
+        assert_eq!(times(history_range(&history, "b", 0, u64::MAX, 2)), [6, 7]);
+        assert_eq!(history.len(), MAX_HISTORY_LENGTH + 1);
+        assert_eq!(times(history_range(&history, "a", 0, u64::MAX, 10)), [0]);
+    }
+}
@@ -0,0 +1,27 @@
+//only icrc1_balance_of of an ICRC-1 ledger, enough for the balance snapshot of token-weighted proposals
+//balances are set by hand with set_balance and live on the heap, they are gone after an upgrade
+use candid::{CandidType, Deserialize, Nat, Principal};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+thread_local! {
+    static BALANCES: RefCell<BTreeMap<Principal, Nat>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+//subaccounts are ignored, members vote with their default account
+#[derive(CandidType, Deserialize)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[ic_cdk::query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    BALANCES.with(|b| b.borrow().get(&account.owner).cloned().unwrap_or_default())
+}
+
+//nat so balances above u64::MAX can be tried too
+#[ic_cdk::update]
+fn set_balance(owner: Principal, balance: Nat) {
+    BALANCES.with(|b| b.borrow_mut().insert(owner, balance));
+}
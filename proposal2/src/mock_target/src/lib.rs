@@ -0,0 +1,40 @@
+//a target for executable proposals when running locally
+//create a proposal with this canister as the action's canister and one of these methods, then end it
+//the calls it got are kept on the heap, they are gone after an upgrade which is fine for tests
+use candid::Principal;
+use std::cell::RefCell;
+
+thread_local! {
+    static CALLS: RefCell<Vec<(Principal, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+fn record(method: &str) {
+    CALLS.with(|c| c.borrow_mut().push((ic_cdk::caller(), method.to_string())));
+}
+
+//replies with the text it got, for the Succeeded status
+#[ic_cdk::update]
+fn echo(text: String) -> String {
+    record("echo");
+    text
+}
+
+//replies with a text longer than MAX_ACTION_REPLY_SIZE, for the truncated flag
+#[ic_cdk::update]
+fn long_reply(length: u32) -> String {
+    record("long_reply");
+    "a".repeat(length as usize)
+}
+
+//traps with the text it got, for the Failed status
+//a text with multi-byte characters checks that the reject message is cut between characters
+#[ic_cdk::update]
+fn fail(message: String) {
+    ic_cdk::trap(&message);
+}
+
+//who called which method, oldest first
+#[ic_cdk::query]
+fn get_calls() -> Vec<(Principal, String)> {
+    CALLS.with(|c| c.borrow().clone())
+}
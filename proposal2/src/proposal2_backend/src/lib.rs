@@ -1,12 +1,44 @@
-use candid::{CandidType, Decode, Deserialize, Encode};
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
 use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
-use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, Storable};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
 
+use ic_cdk_timers::TimerId;
 use ic_stable_structures::storable::Bound;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
 use std::{borrow::Cow, cell::RefCell};
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 const MAX_VALUE_SIZE: u32 = 5000;
-#[derive(CandidType, Deserialize)]
+//a vote can hold a ranking of up to MAX_OPTIONS options
+const MAX_VOTE_SIZE: u32 = 400;
+const MAX_MEMBER_SIZE: u32 = 100;
+const MAX_OPTIONS: usize = 20;
+const MAX_OPTION_LENGTH: usize = 100;
+//one count per option for every round of an instant runoff
+const MAX_RESULTS_SIZE: u32 = 8000;
+//list_proposals returns at most this many proposals per page
+const MAX_PAGE_SIZE: usize = 50;
+const MAX_REASON_LENGTH: usize = 500;
+//the salt is only there to make the hash impossible to guess, 64 bytes is more than enough
+const MAX_SALT_LENGTH: usize = 64;
+const MAX_COMMENT_LENGTH: usize = 2000;
+const MAX_TOPIC_LENGTH: usize = 64;
+//principal + topic + the candid header
+const MAX_DELEGATION_KEY_SIZE: u32 = 200;
+//an action holds the candid args of the call and later its reply, both are limited so the action fits in its bound
+const MAX_ACTION_SIZE: u32 = 10_000;
+const MAX_ACTION_ARGS_SIZE: usize = 4096;
+const MAX_ACTION_REPLY_SIZE: usize = 4096;
+const MAX_METHOD_NAME_LENGTH: usize = 100;
+//rule used for proposals created without one (and for the ones stored before rules existed): no quorum, simple majority
+const DEFAULT_RULE: VotingRule = VotingRule {
+    quorum: 0,
+    approval_percentage: 50,
+};
+//principals that are not in the weight table vote with this weight
+const DEFAULT_WEIGHT: u32 = 1;
+#[derive(CandidType, Deserialize, Clone, Copy)]
 enum Choice {
     Approve,
     Reject,
@@ -16,10 +48,139 @@ enum Choice {
 enum VoteError {
     AlreadyVoted,
     ProposalIsNotActive,
-  
+
     NoSuchProposal,
     AccessRejected,
     UpdateError,
+    //the caller has a weight of 0 in the weight table
+    NoVotingPower,
+    //approval_percentage must be below 100
+    InvalidVotingRule,
+    //the deadline of an active proposal must be in the future
+    InvalidDeadline,
+    //the delegation would make a chain that comes back to the delegator
+    DelegationCycle,
+    //topics can't be empty or longer than MAX_TOPIC_LENGTH
+    InvalidTopic,
+    //the method name is empty or too long, or the args are bigger than MAX_ACTION_ARGS_SIZE
+    //also used to add someone who is already a member or remove someone who is not
+    //and for actions on proposals with options, they have no accepted outcome
+    InvalidAction,
+    //less than 2 or more than MAX_OPTIONS options, or an empty or too long option
+    InvalidOptions,
+    //a Choice on a proposal with options, a ballot on one without, or a ballot that doesn't fit the options
+    InvalidBallot,
+    //the encoded proposal doesn't fit in MAX_VALUE_SIZE, storing it would trap
+    ProposalTooLarge { size: u32, max: u32 },
+    //the allocated id is already used, nothing was overwritten
+    DuplicateProposal,
+    //the last id (u64::MAX) was given out, no more proposals can be made
+    NoProposalIdLeft,
+    //edits are locked once somebody voted, voters must see what they voted on
+    HasVotes,
+    //only an ended proposal can be reopened
+    ProposalNotEnded,
+    //the action of the proposal already ran, reopening it can't undo that
+    ActionAlreadyExecuted,
+    //the reason for reopening is empty or longer than MAX_REASON_LENGTH
+    InvalidReason,
+    //the proposal uses a secret ballot, votes go through commit_vote and reveal_vote
+    //also returned when reopening a secret ballot, its commit phase is over for good
+    SecretBallot,
+    //commit_vote or reveal_vote on a proposal without a secret ballot
+    NotSecretBallot,
+    //the reveal deadline must come after the voting deadline, and proposals with options can't be secret
+    InvalidRevealDeadline,
+    //the hash is not 32 bytes
+    InvalidCommitment,
+    //reveals are only accepted between the deadline and the reveal deadline
+    NotInRevealPhase,
+    NoCommitment,
+    //the choice and salt don't hash to the committed value, or the salt is too long
+    RevealMismatch,
+    //a commitment is revealed only once
+    AlreadyRevealed,
+    //a token weighted proposal was asked for but set_ledger was never called
+    NoLedger,
+    //the ledger could not be reached or rejected icrc1_balance_of
+    LedgerUnavailable,
+    NoSuchComment,
+    //the comment is empty or longer than MAX_COMMENT_LENGTH
+    InvalidComment,
+    //deleted comments can't be edited or replied to
+    CommentDeleted,
+    //the proposal already has a comment with the last id (u32::MAX)
+    NoCommentIdLeft,
+}
+//how a proposal is decided when it ends
+//quorum is the total weight (approve + reject + pass) that has to be reached
+//the proposal is accepted when approve is more than approval_percentage percent of approve + reject
+#[derive(CandidType, Deserialize, Clone, Copy)]
+struct VotingRule {
+    quorum: u64,
+    approval_percentage: u8,
+}
+//Winner and Tie are for proposals with options, Accepted and Rejected for the others
+#[derive(CandidType, Deserialize, Clone, Copy)]
+enum ProposalOutcome {
+    Accepted,
+    Rejected,
+    NoQuorum,
+    Winner { option: u32 },
+    Tie,
+}
+//how the options of a proposal are voted on
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+enum VotingMode {
+    SingleChoice,
+    //each voter can pick as many options as they like, every pick gets their full weight
+    Approval,
+    //voters rank options, tallied with instant runoff
+    RankedChoice,
+}
+//what a voter sends for a proposal with options, the numbers are indexes in the options list
+#[derive(CandidType, Deserialize, Clone)]
+enum Ballot {
+    Single(u32),
+    Approval(Vec<u32>),
+    //most preferred first, options that are not ranked are never counted for this ballot
+    Ranked(Vec<u32>),
+}
+//counts of one round, eliminated options keep a count of 0
+#[derive(CandidType, Deserialize, Clone)]
+struct VotingRound {
+    counts: Vec<u64>,
+    eliminated: Option<u32>,
+}
+//single choice and approval only have one round
+#[derive(CandidType, Deserialize, Clone)]
+struct OptionResults {
+    rounds: Vec<VotingRound>,
+    winner: Option<u32>,
+}
+//votes summed by weight, the approve/reject/pass fields of the proposal stay a count of voters
+#[derive(CandidType, Deserialize, Clone, Copy, Default)]
+struct Tally {
+    approve: u64,
+    reject: u64,
+    pass: u64,
+}
+impl Tally {
+    fn add(&mut self, choice: Choice, weight: u64) {
+        match choice {
+            //token balances can be big enough to overflow
+            Choice::Approve => self.approve = self.approve.saturating_add(weight),
+            Choice::Reject => self.reject = self.reject.saturating_add(weight),
+            Choice::Pass => self.pass = self.pass.saturating_add(weight),
+        }
+    }
+    fn remove(&mut self, choice: Choice, weight: u64) {
+        match choice {
+            Choice::Approve => self.approve = self.approve.saturating_sub(weight),
+            Choice::Reject => self.reject = self.reject.saturating_sub(weight),
+            Choice::Pass => self.pass = self.pass.saturating_sub(weight),
+        }
+    }
 }
 //Data for proposal
 #[derive(CandidType, Deserialize)] //automatically implement the CandidType and Deserialize traits for the Proposal struct, making it possible to serialize and deserialize instances of this struct using Candid.
@@ -29,19 +190,356 @@ struct Proposal {
     reject: u32,
     pass: u32,
     is_active: bool,
-    voted: Vec<candid::Principal>,
+    //only set on proposals stored before VOTER_MAP existed, post_upgrade moves these voters there
+    voted: Option<Vec<candid::Principal>>,
     owner: candid::Principal,
+    //options so proposals stored before voting rules still decode, None means DEFAULT_RULE
+    rule: Option<VotingRule>,
+    tally: Option<Tally>,
+    //set by end_proposal or by the deadline timer, tally is not changed after that
+    outcome: Option<ProposalOutcome>,
+    //nanoseconds since the epoch like ic_cdk::api::time()
+    deadline: Option<u64>,
+    ended_at: Option<u64>,
+    //delegations for this topic are used before the global ones
+    topic: Option<String>,
+    //proposals with options are voted with vote_options instead of the approve/reject/pass counters
+    options: Option<Vec<String>>,
+    mode: Option<VotingMode>,
+    //set for a secret ballot: votes are committed until deadline and revealed until reveal_deadline
+    //the counters stay at 0 until the reveal phase is over
+    reveal_deadline: Option<u64>,
+    //commitments that were never revealed, set when a secret ballot ends
+    abstained: Option<u32>,
+    //the ICRC-1 ledger the balances in BALANCE_MAP were read from, None when votes use WEIGHT_MAP
+    ledger: Option<Principal>,
 }
 
 #[derive(CandidType, Deserialize)]
 struct CreateProposal {
     description: String,
     is_active: bool,
+    rule: Option<VotingRule>,
+    deadline: u64,
+    topic: Option<String>,
+    action: Option<ProposalAction>,
+    //empty for an Approve/Reject/Pass proposal
+    options: Vec<String>,
+    mode: Option<VotingMode>,
+    //makes the proposal a secret ballot
+    reveal_deadline: Option<u64>,
+    //weights votes by the ledger balances of the members instead of WEIGHT_MAP, needs set_ledger first
+    token_weighted: Option<bool>,
+}
+
+//a hidden vote of a secret ballot, hash is sha256 of the choice byte followed by the salt
+//the choice byte is 0 for Approve, 1 for Reject and 2 for Pass
+#[derive(CandidType, Deserialize, Clone)]
+struct Commitment {
+    hash: Vec<u8>,
+    weight: u64,
+    committed_at: u64,
+    revealed: Option<Choice>,
+}
+
+//what can be seen of a secret ballot while it runs, no choices
+#[derive(CandidType)]
+struct SecretBallotStatus {
+    commitments: u32,
+    revealed: u32,
+    deadline: Option<u64>,
+    reveal_deadline: u64,
+}
+
+//what the owner can change with edit_proposal, is_active can only turn a draft into an active proposal
+#[derive(CandidType, Deserialize)]
+struct EditProposal {
+    description: String,
+    is_active: bool,
+    deadline: u64,
+}
+
+//one version of the text of a proposal, revision 0 is the one it was created with
+//at is None for the first text of proposals created before revisions were kept
+#[derive(CandidType, Deserialize, Clone)]
+struct Revision {
+    description: String,
+    deadline: Option<u64>,
+    at: Option<u64>,
+    author: Principal,
+}
+
+//kept every time an ended proposal is opened again
+#[derive(CandidType, Deserialize, Clone)]
+struct Reopening {
+    by: Principal,
+    at: u64,
+    reason: String,
+    previous_outcome: Option<ProposalOutcome>,
+    previous_ended_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+enum ProposalStatus {
+    Active,
+    Ended,
+    //made inactive and never ended, it can still be activated with edit_proposal
+    Draft,
+}
+
+//every filter that is set has to match, text is matched in the description without case
+//start_after is the next field of the previous page
+#[derive(CandidType, Deserialize)]
+struct ProposalFilter {
+    status: Option<ProposalStatus>,
+    owner: Option<Principal>,
+    text: Option<String>,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+}
+
+//next is None on the last page
+#[derive(CandidType)]
+struct ProposalPage {
+    proposals: Vec<(u64, Proposal)>,
+    next: Option<u64>,
+}
+
+//a comment on a proposal, reply_to is the id of the comment it answers on the same proposal
+//deleting keeps the entry with an empty text so the replies still point somewhere
+#[derive(CandidType, Deserialize, Clone)]
+struct Comment {
+    author: Principal,
+    text: String,
+    created_at: u64,
+    edited_at: Option<u64>,
+    reply_to: Option<u32>,
+    deleted: bool,
+}
+
+//thread None lists the top level comments, Some(id) the direct replies to that comment
+#[derive(CandidType, Deserialize)]
+struct CommentFilter {
+    thread: Option<u32>,
+    start_after: Option<u32>,
+    limit: Option<u32>,
+}
+
+//next is None on the last page
+#[derive(CandidType)]
+struct CommentPage {
+    comments: Vec<(u32, Comment)>,
+    next: Option<u32>,
+}
+
+//one voter of one proposal, kept in VOTER_MAP instead of inside the proposal
+//choice is None for voters migrated from the old voted list, their choice was never stored
+#[derive(CandidType, Deserialize, Clone)]
+struct VoteRecord {
+    choice: Option<Choice>,
+    weight: u64,
+    voted_at: Option<u64>,
+    //set instead of choice on proposals with options
+    ballot: Option<Ballot>,
+}
+
+impl Storable for VoteRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VOTE_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+//what the canister does when the proposal is accepted
+//args are already candid encoded, the canister doesn't look into them
+#[derive(CandidType, Deserialize, Clone)]
+enum ProposalAction {
+    CallCanister {
+        canister: Principal,
+        method: String,
+        args: Vec<u8>,
+    },
+    AddMember {
+        member: Principal,
+    },
+    RemoveMember {
+        member: Principal,
+    },
+}
+
+//the reply is cut at MAX_ACTION_REPLY_SIZE, truncated tells if that happened
+#[derive(CandidType, Deserialize, Clone)]
+enum ActionStatus {
+    Pending,
+    Executing,
+    Succeeded { reply: Vec<u8>, truncated: bool },
+    //code is the rejection code of the call, None when the canister itself refused the action
+    Failed { code: Option<i32>, message: String },
+    //the proposal ended without being accepted
+    Skipped,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Member {
+    added_at: u64,
+    //the proposal that added the member, None for the deployer
+    proposal: Option<u64>,
+}
+
+impl Storable for Member {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_MEMBER_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+struct ProposalActionState {
+    action: ProposalAction,
+    status: ActionStatus,
+    executed_at: Option<u64>,
+}
+
+impl Storable for ProposalActionState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_ACTION_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+//a delegation for one topic, or for every topic when topic is None
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct DelegationKey {
+    delegator: Principal,
+    topic: Option<String>,
+}
+
+impl Storable for OptionResults {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_RESULTS_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+//the ledger token weighted proposals read balances from
+#[derive(CandidType, Deserialize, Default)]
+struct LedgerConfig {
+    ledger: Option<Principal>,
+}
+
+//ICRC-1 account, only the owner is used since members vote with their default account
+#[derive(CandidType, Deserialize)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+impl Storable for LedgerConfig {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_MEMBER_SIZE,
+        is_fixed_size: false,
+    };
 }
+
+impl Storable for Commitment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VOTE_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for Revision {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VALUE_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for Comment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VALUE_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for Reopening {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_VALUE_SIZE,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for DelegationKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    const BOUND: Bound = Bound::Bounded {
+        max_size: MAX_DELEGATION_KEY_SIZE,
+        is_fixed_size: false,
+    };
+}
+
 //The Storable trait is implemented for the Proposal struct to enable it to be stored in stable memory.
 impl Storable for Proposal {
     //Serializa
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
     //Deserialize
@@ -61,7 +559,771 @@ static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> = RefCell::new(
 //proposal map to hold proposals
 static PROPOSAL_MAP:RefCell<StableBTreeMap<u64,Proposal,Memory>>=RefCell::new(StableBTreeMap::init(
     MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(0)))
-))
+));
+//voting weight of each principal, managed by the controllers of the canister
+static WEIGHT_MAP:RefCell<StableBTreeMap<Principal,u32,Memory>>=RefCell::new(StableBTreeMap::init(
+    MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(1)))
+));
+//key is (proposal key, voter) so the voters of a proposal are next to each other
+static VOTER_MAP:RefCell<StableBTreeMap<(u64,Principal),VoteRecord,Memory>>=RefCell::new(StableBTreeMap::init(
+    MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(2)))
+));
+//who each principal delegated their vote to
+static DELEGATION_MAP:RefCell<StableBTreeMap<DelegationKey,Principal,Memory>>=RefCell::new(StableBTreeMap::init(
+    MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(3)))
+));
+//the action of each proposal that has one, with the result of its execution
+static ACTION_MAP:RefCell<StableBTreeMap<u64,ProposalActionState,Memory>>=RefCell::new(StableBTreeMap::init(
+    MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(4)))
+));
+//only members can create and vote on proposals, they are added and removed by proposals
+static MEMBER_MAP:RefCell<StableBTreeMap<Principal,Member,Memory>>=RefCell::new(StableBTreeMap::init(
+    MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(5)))
+));
+//round by round results of proposals with options, written when they end
+static RESULTS_MAP:RefCell<StableBTreeMap<u64,OptionResults,Memory>>=RefCell::new(StableBTreeMap::init(
+    MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(6)))
+));
+//id given to the next proposal
+static NEXT_PROPOSAL_ID:RefCell<StableCell<u64,Memory>>=RefCell::new(StableCell::init(
+    MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(7))),
+    0,
+).expect("failed to init the next proposal id"));
+//every text a proposal had, key is (proposal key, revision)
+static REVISION_MAP:RefCell<StableBTreeMap<(u64,u32),Revision,Memory>>=RefCell::new(StableBTreeMap::init(
+    MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(8)))
+));
+//audit log of reopened proposals, key is (proposal key, n-th reopening)
+static REOPEN_MAP:RefCell<StableBTreeMap<(u64,u32),Reopening,Memory>>=RefCell::new(StableBTreeMap::init(
+    MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(9)))
+));
+//commitments of secret ballots, key is (proposal key, voter)
+//they go to VOTER_MAP when the reveal phase ends
+static COMMIT_MAP:RefCell<StableBTreeMap<(u64,Principal),Commitment,Memory>>=RefCell::new(StableBTreeMap::init(
+    MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(10)))
+));
+static LEDGER:RefCell<StableCell<LedgerConfig,Memory>>=RefCell::new(StableCell::init(
+    MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(11))),
+    LedgerConfig::default(),
+).expect("failed to init the ledger config"));
+//balance of every member when a token weighted proposal was created, key is (proposal key, member)
+static BALANCE_MAP:RefCell<StableBTreeMap<(u64,Principal),u64,Memory>>=RefCell::new(StableBTreeMap::init(
+    MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(12)))
+));
+//discussion of every proposal, key is (proposal key, comment id)
+static COMMENT_MAP:RefCell<StableBTreeMap<(u64,u32),Comment,Memory>>=RefCell::new(StableBTreeMap::init(
+    MEMORY_MANAGER.with(|m|m.borrow().get(MemoryId::new(13)))
+));
+//timers are not kept across upgrades, post_upgrade sets them again from the deadlines in PROPOSAL_MAP
+//we keep the ids to cancel the timer when a proposal is ended by hand
+static DEADLINE_TIMERS:RefCell<BTreeMap<u64,TimerId>>=RefCell::default();
+}
+
+impl Proposal {
+    //proposals ended before ended_at existed have no tally either, they are not drafts
+    fn status(&self) -> ProposalStatus {
+        if self.is_active {
+            ProposalStatus::Active
+        } else if self.has_ended() || self.tally.is_none() {
+            ProposalStatus::Ended
+        } else {
+            ProposalStatus::Draft
+        }
+    }
+
+    //proposals stored before weights existed only have the counters, every vote there weighed 1
+    fn weighted_tally(&self) -> Tally {
+        self.tally.unwrap_or(Tally {
+            approve: self.approve as u64,
+            reject: self.reject as u64,
+            pass: self.pass as u64,
+        })
+    }
+
+    //the deadline timer fires at the end of the reveal phase for a secret ballot
+    fn end_time(&self) -> Option<u64> {
+        self.reveal_deadline.or(self.deadline)
+    }
+
+    fn in_reveal_phase(&self, now: u64) -> bool {
+        match (self.deadline, self.reveal_deadline) {
+            (Some(deadline), Some(reveal_deadline)) => {
+                self.is_active && deadline <= now && now < reveal_deadline
+            }
+            _ => false,
+        }
+    }
+
+    //a proposal that was never active has no outcome and no end time, it is a draft
+    fn has_ended(&self) -> bool {
+        self.ended_at.is_some() || self.outcome.is_some()
+    }
+
+    //the timer may not have fired yet when the deadline passed, so votes check the time too
+    fn is_open(&self) -> bool {
+        self.is_active
+            && self
+                .deadline
+                .is_none_or(|deadline| ic_cdk::api::time() < deadline)
+    }
+
+    //freezes the tally with the delegated weight in it and records the outcome
+    //proposals with options get their rounds written to RESULTS_MAP
+    fn close(&mut self, key: u64) {
+        let rule = self.rule.unwrap_or(DEFAULT_RULE);
+        self.is_active = false;
+        self.ended_at = Some(ic_cdk::api::time());
+        if self.reveal_deadline.is_some() {
+            publish_reveals(key, self);
+        }
+        if let Some(options) = &self.options {
+            let ballots = ballots_with_delegations(key, self);
+            let total = ballots
+                .iter()
+                .fold(0u64, |total, (_, weight)| total.saturating_add(*weight));
+            let results = count_options(
+                options.len(),
+                self.mode.unwrap_or(VotingMode::SingleChoice),
+                &ballots,
+            );
+            self.outcome = Some(option_outcome(&rule, total, results.winner));
+            RESULTS_MAP.with(|r| r.borrow_mut().insert(key, results));
+            return;
+        }
+        let tally = tally_with_delegations(key, self);
+        self.tally = Some(tally);
+        self.outcome = Some(decide(&rule, &tally));
+    }
+}
+
+//outcome of a proposal with options, total is all the weight of the ballots
+fn option_outcome(rule: &VotingRule, total: u64, winner: Option<u32>) -> ProposalOutcome {
+    if total < rule.quorum {
+        return ProposalOutcome::NoQuorum;
+    }
+    match winner {
+        Some(option) => ProposalOutcome::Winner { option },
+        None => ProposalOutcome::Tie,
+    }
+}
+
+//the option with the most weight, None when nobody voted or the first place is shared
+fn unique_max(counts: &[u64]) -> Option<u32> {
+    let max = *counts.iter().max()?;
+    let mut leaders = counts.iter().enumerate().filter(|(_, c)| **c == max);
+    match (leaders.next(), leaders.next()) {
+        (Some((i, _)), None) if max > 0 => Some(i as u32),
+        _ => None,
+    }
+}
+
+//counts the ballots of a proposal with options
+//ranked choice runs instant runoff: each round a ballot counts for its best option still in the race,
+//an option with more than half of the counted weight wins, otherwise the weakest one is eliminated
+fn count_options(options: usize, mode: VotingMode, ballots: &[(Ballot, u64)]) -> OptionResults {
+    let mut counts = vec![0u64; options];
+    match mode {
+        VotingMode::SingleChoice | VotingMode::Approval => {
+            for (ballot, weight) in ballots {
+                let picks: &[u32] = match ballot {
+                    Ballot::Single(option) => std::slice::from_ref(option),
+                    Ballot::Approval(picks) => picks,
+                    Ballot::Ranked(_) => &[],
+                };
+                //saturating like Tally::add, token balances can be big enough to overflow
+                for pick in picks {
+                    let count = &mut counts[*pick as usize];
+                    *count = count.saturating_add(*weight);
+                }
+            }
+            let winner = unique_max(&counts);
+            OptionResults {
+                rounds: vec![VotingRound {
+                    counts,
+                    eliminated: None,
+                }],
+                winner,
+            }
+        }
+        VotingMode::RankedChoice => {
+            let mut remaining: BTreeSet<u32> = (0..options as u32).collect();
+            let mut rounds = vec![];
+            loop {
+                let mut counts = vec![0u64; options];
+                for (ballot, weight) in ballots {
+                    if let Ballot::Ranked(ranking) = ballot {
+                        if let Some(best) = ranking.iter().find(|o| remaining.contains(o)) {
+                            let count = &mut counts[*best as usize];
+                            *count = count.saturating_add(*weight);
+                        }
+                    }
+                }
+                //u128 so the sum and the doubling can't overflow
+                let counted: u128 = counts.iter().map(|c| *c as u128).sum();
+                let leader = unique_max(&counts);
+                let majority = leader.filter(|l| counts[*l as usize] as u128 * 2 > counted);
+                if counted == 0 || majority.is_some() || remaining.len() <= 2 {
+                    rounds.push(VotingRound {
+                        counts,
+                        eliminated: None,
+                    });
+                    return OptionResults {
+                        rounds,
+                        winner: majority.or(leader),
+                    };
+                }
+                //on a tie for last place the option with the highest index goes, so the result is deterministic
+                let eliminated = *remaining
+                    .iter()
+                    .rev()
+                    .min_by_key(|o| counts[**o as usize])
+                    .expect("more than two options remain");
+                remaining.remove(&eliminated);
+                rounds.push(VotingRound {
+                    counts,
+                    eliminated: Some(eliminated),
+                });
+            }
+        }
+    }
+}
+
+//the delegate of a principal for a topic, a delegation for the topic wins over the global one
+fn delegate_of(delegator: Principal, topic: &Option<String>) -> Option<Principal> {
+    DELEGATION_MAP.with(|d| {
+        let map = d.borrow();
+        topic
+            .as_ref()
+            .and_then(|topic| {
+                map.get(&DelegationKey {
+                    delegator,
+                    topic: Some(topic.clone()),
+                })
+            })
+            .or_else(|| {
+                map.get(&DelegationKey {
+                    delegator,
+                    topic: None,
+                })
+            })
+    })
+}
+
+//follows the delegation chain from start for a topic and tells if it passes through target
+fn chain_reaches(start: Principal, target: Principal, topic: &Option<String>) -> bool {
+    let mut visited = BTreeSet::new();
+    let mut current = Some(start);
+    while let Some(principal) = current {
+        if principal == target {
+            return true;
+        }
+        if !visited.insert(principal) {
+            return false;
+        }
+        current = delegate_of(principal, topic);
+    }
+    false
+}
+
+//moves the revealed votes of a secret ballot into VOTER_MAP and the counters, the others are abstentions
+fn publish_reveals(key: u64, proposal: &mut Proposal) {
+    let start = (key, Principal::from_slice(&[]));
+    let commitments: Vec<(Principal, Commitment)> = COMMIT_MAP.with(|c| {
+        c.borrow()
+            .range(start..)
+            .take_while(|((k, _), _)| *k == key)
+            .map(|((_, voter), commitment)| (voter, commitment))
+            .collect()
+    });
+    let mut tally = proposal.weighted_tally();
+    let mut abstained = 0;
+    for (voter, commitment) in commitments {
+        let Some(choice) = commitment.revealed else {
+            abstained += 1;
+            continue;
+        };
+        match choice {
+            Choice::Approve => proposal.approve += 1,
+            Choice::Reject => proposal.reject += 1,
+            Choice::Pass => proposal.pass += 1,
+        };
+        tally.add(choice, commitment.weight);
+        let record = VoteRecord {
+            choice: Some(choice),
+            weight: commitment.weight,
+            voted_at: Some(commitment.committed_at),
+            ballot: None,
+        };
+        VOTER_MAP.with(|v| v.borrow_mut().insert((key, voter), record));
+    }
+    proposal.tally = Some(tally);
+    proposal.abstained = Some(abstained);
+}
+
+fn commitment_hash(choice: Choice, salt: &[u8]) -> Vec<u8> {
+    let choice_byte: u8 = match choice {
+        Choice::Approve => 0,
+        Choice::Reject => 1,
+        Choice::Pass => 2,
+    };
+    let mut hasher = Sha256::new();
+    hasher.update([choice_byte]);
+    hasher.update(salt);
+    hasher.finalize().to_vec()
+}
+
+//the vote each delegator who didn't vote gets through their delegation chain, with the delegator's weight
+//it is the vote of the first principal in the chain that voted directly, if nobody did the delegator is not counted
+fn delegated_votes(key: u64, proposal: &Proposal) -> Vec<(VoteRecord, u64)> {
+    let mut votes = vec![];
+    let delegators: BTreeSet<Principal> =
+        DELEGATION_MAP.with(|d| d.borrow().iter().map(|(k, _)| k.delegator).collect());
+    for delegator in delegators {
+        //principals that were removed from the members keep their delegations but lose their vote
+        //a commitment on a secret ballot counts as voting directly, even when it is never revealed
+        if !is_member(&delegator)
+            || VOTER_MAP.with(|v| v.borrow().contains_key(&(key, delegator)))
+            || COMMIT_MAP.with(|c| c.borrow().contains_key(&(key, delegator)))
+        {
+            continue;
+        }
+        let mut visited = BTreeSet::from([delegator]);
+        let mut current = delegate_of(delegator, &proposal.topic);
+        while let Some(principal) = current {
+            if !visited.insert(principal) {
+                break;
+            }
+            if let Some(record) = VOTER_MAP.with(|v| v.borrow().get(&(key, principal))) {
+                votes.push((record, vote_weight(key, proposal, &delegator)));
+                break;
+            }
+            current = delegate_of(principal, &proposal.topic);
+        }
+    }
+    votes
+}
+
+//direct votes plus the delegated ones
+fn tally_with_delegations(key: u64, proposal: &Proposal) -> Tally {
+    let mut tally = proposal.weighted_tally();
+    for (record, weight) in delegated_votes(key, proposal) {
+        if let Some(choice) = record.choice {
+            tally.add(choice, weight);
+        }
+    }
+    tally
+}
+
+//every ballot of a proposal with options, the delegated ones are copies of the delegate's ballot
+fn ballots_with_delegations(key: u64, proposal: &Proposal) -> Vec<(Ballot, u64)> {
+    let start = (key, Principal::from_slice(&[]));
+    let mut ballots: Vec<(Ballot, u64)> = VOTER_MAP.with(|v| {
+        v.borrow()
+            .range(start..)
+            .take_while(|((k, _), _)| *k == key)
+            .filter_map(|(_, record)| record.ballot.map(|ballot| (ballot, record.weight)))
+            .collect()
+    });
+    for (record, weight) in delegated_votes(key, proposal) {
+        if let Some(ballot) = record.ballot {
+            ballots.push((ballot, weight));
+        }
+    }
+    ballots
+}
+
+fn validate_options(options: &[String], action: &Option<ProposalAction>) -> Result<(), VoteError> {
+    if options.len() < 2
+        || options.len() > MAX_OPTIONS
+        || options
+            .iter()
+            .any(|o| o.trim().is_empty() || o.len() > MAX_OPTION_LENGTH)
+    {
+        return Err(VoteError::InvalidOptions);
+    }
+    if action.is_some() {
+        return Err(VoteError::InvalidAction);
+    }
+    Ok(())
+}
+
+//indexes must be in range and not repeated, approval and ranked ballots need at least one option
+fn validate_ballot(options: usize, mode: VotingMode, ballot: &Ballot) -> Result<(), VoteError> {
+    let picks: &[u32] = match (mode, ballot) {
+        (VotingMode::SingleChoice, Ballot::Single(option)) => std::slice::from_ref(option),
+        (VotingMode::Approval, Ballot::Approval(picks)) => picks,
+        (VotingMode::RankedChoice, Ballot::Ranked(ranking)) => ranking,
+        _ => return Err(VoteError::InvalidBallot),
+    };
+    let unique: BTreeSet<&u32> = picks.iter().collect();
+    if picks.is_empty()
+        || unique.len() != picks.len()
+        || picks.iter().any(|p| *p as usize >= options)
+    {
+        return Err(VoteError::InvalidBallot);
+    }
+    Ok(())
+}
+
+//ids go up from the last one given, proposals made when the caller chose the key are skipped over
+//the next id stays at u64::MAX once that one is given, the map then refuses it as a duplicate
+fn next_proposal_id() -> Result<u64, VoteError> {
+    let after_last = match PROPOSAL_MAP.with(|p| p.borrow().last_key_value()) {
+        Some((last, _)) => last.checked_add(1).ok_or(VoteError::NoProposalIdLeft)?,
+        None => 0,
+    };
+    let key = NEXT_PROPOSAL_ID.with(|n| *n.borrow().get()).max(after_last);
+    if PROPOSAL_MAP.with(|p| p.borrow().contains_key(&key)) {
+        return Err(VoteError::DuplicateProposal);
+    }
+    NEXT_PROPOSAL_ID
+        .with(|n| n.borrow_mut().set(key.saturating_add(1)))
+        .expect("failed to set the next proposal id");
+    Ok(key)
+}
+
+//commitments of a secret ballot count as votes
+fn has_votes(key: u64) -> bool {
+    let start = (key, Principal::from_slice(&[]));
+    VOTER_MAP.with(|v| {
+        v.borrow()
+            .range(start..)
+            .next()
+            .is_some_and(|((k, _), _)| k == key)
+    }) || COMMIT_MAP.with(|c| {
+        c.borrow()
+            .range(start..)
+            .next()
+            .is_some_and(|((k, _), _)| k == key)
+    })
+}
+
+//the entries of a map keyed by (proposal key, n) that belong to one proposal, in order
+fn entries_of<V: Storable>(map: &StableBTreeMap<(u64, u32), V, Memory>, key: u64) -> Vec<V> {
+    map.range((key, 0)..)
+        .take_while(|((k, _), _)| *k == key)
+        .map(|(_, v)| v)
+        .collect()
+}
+
+fn validate_comment(text: &str) -> Result<(), VoteError> {
+    if text.trim().is_empty() || text.len() > MAX_COMMENT_LENGTH {
+        return Err(VoteError::InvalidComment);
+    }
+    Ok(())
+}
+
+//a comment the caller wrote that is not deleted
+fn own_comment(key: u64, id: u32) -> Result<Comment, VoteError> {
+    let comment = COMMENT_MAP
+        .with(|c| c.borrow().get(&(key, id)))
+        .ok_or(VoteError::NoSuchComment)?;
+    if comment.author != ic_cdk::caller() {
+        return Err(VoteError::AccessRejected);
+    }
+    if comment.deleted {
+        return Err(VoteError::CommentDeleted);
+    }
+    Ok(comment)
+}
+
+fn add_revision(key: u64, revision: Revision) {
+    REVISION_MAP.with(|r| {
+        let mut map = r.borrow_mut();
+        let next = entries_of(&map, key).len() as u32;
+        map.insert((key, next), revision);
+    });
+}
+
+//the tally of the direct votes only, used when an ended proposal that had delegated weight in its tally is reopened
+//migrated voters have no stored choice, they are the part of the counters no stored choice accounts for
+fn direct_tally(key: u64, proposal: &Proposal) -> Tally {
+    let start = (key, Principal::from_slice(&[]));
+    let mut tally = Tally::default();
+    let mut stored = Tally::default();
+    VOTER_MAP.with(|v| {
+        for (_, record) in v
+            .borrow()
+            .range(start..)
+            .take_while(|((k, _), _)| *k == key)
+        {
+            if let Some(choice) = record.choice {
+                tally.add(choice, record.weight);
+                stored.add(choice, 1);
+            }
+        }
+    });
+    let migrated = [
+        (Choice::Approve, proposal.approve as u64, stored.approve),
+        (Choice::Reject, proposal.reject as u64, stored.reject),
+        (Choice::Pass, proposal.pass as u64, stored.pass),
+    ];
+    for (choice, voters, stored) in migrated {
+        tally.add(
+            choice,
+            voters.saturating_sub(stored) * DEFAULT_WEIGHT as u64,
+        );
+    }
+    tally
+}
+
+fn validate_topic(topic: &Option<String>) -> Result<(), VoteError> {
+    match topic {
+        Some(topic) if topic.trim().is_empty() || topic.len() > MAX_TOPIC_LENGTH => {
+            Err(VoteError::InvalidTopic)
+        }
+        _ => Ok(()),
+    }
+}
+
+//runs when the deadline of a proposal is reached
+fn close_at_deadline(key: u64) {
+    DEADLINE_TIMERS.with(|t| t.borrow_mut().remove(&key));
+    PROPOSAL_MAP.with(|p| {
+        let mut map = p.borrow_mut();
+        if let Some(mut proposal) = map.get(&key) {
+            if proposal.is_active {
+                proposal.close(key);
+                let outcome = proposal.outcome;
+                map.insert(key, proposal);
+                run_action(key, outcome);
+            }
+        }
+    });
+}
+
+fn validate_action(action: &ProposalAction) -> Result<(), VoteError> {
+    let valid = match action {
+        ProposalAction::CallCanister { method, args, .. } => {
+            !method.is_empty()
+                && method.len() <= MAX_METHOD_NAME_LENGTH
+                && args.len() <= MAX_ACTION_ARGS_SIZE
+        }
+        ProposalAction::AddMember { member } => !is_member(member),
+        ProposalAction::RemoveMember { member } => is_member(member),
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(VoteError::InvalidAction)
+    }
+}
+
+fn is_member(principal: &Principal) -> bool {
+    MEMBER_MAP.with(|m| m.borrow().contains_key(principal))
+}
+
+fn check_member() -> Result<Principal, VoteError> {
+    require_member(ic_cdk::caller())
+}
+
+fn require_member(principal: Principal) -> Result<Principal, VoteError> {
+    if is_member(&principal) {
+        Ok(principal)
+    } else {
+        Err(VoteError::AccessRejected)
+    }
+}
+
+fn add_member(member: Principal, proposal: Option<u64>, added_at: u64) {
+    let value = Member { added_at, proposal };
+    MEMBER_MAP.with(|m| m.borrow_mut().insert(member, value));
+}
+
+//membership can change between the creation and the end of the proposal, so it is checked again here
+fn change_membership(key: u64, action: &ProposalAction, now: u64) -> ActionStatus {
+    let refuse = |message: &str| ActionStatus::Failed {
+        code: None,
+        message: message.to_string(),
+    };
+    match action {
+        ProposalAction::AddMember { member } if !is_member(member) => {
+            add_member(*member, Some(key), now)
+        }
+        ProposalAction::AddMember { .. } => return refuse("already a member"),
+        ProposalAction::RemoveMember { member } if is_member(member) => {
+            //without members nobody could make a proposal to add one again
+            if MEMBER_MAP.with(|m| m.borrow().len()) == 1 {
+                return refuse("the last member can't be removed");
+            }
+            MEMBER_MAP.with(|m| m.borrow_mut().remove(member));
+        }
+        ProposalAction::RemoveMember { .. } => return refuse("not a member"),
+        ProposalAction::CallCanister { .. } => return refuse("not a membership action"),
+    }
+    ActionStatus::Succeeded {
+        reply: vec![],
+        truncated: false,
+    }
+}
+
+//called when a proposal ends, only a pending action of an accepted proposal is executed
+//the call is async so it is spawned, the status goes to Executing first so it can never run twice
+fn run_action(key: u64, outcome: Option<ProposalOutcome>) {
+    let Some(mut state) = ACTION_MAP.with(|a| a.borrow().get(&key)) else {
+        return;
+    };
+    if !matches!(state.status, ActionStatus::Pending) {
+        return;
+    }
+    if !matches!(outcome, Some(ProposalOutcome::Accepted)) {
+        state.status = ActionStatus::Skipped;
+        ACTION_MAP.with(|a| a.borrow_mut().insert(key, state));
+        return;
+    }
+    //membership changes don't call anything, they are applied right away
+    let (canister, method, args) = match &state.action {
+        ProposalAction::CallCanister {
+            canister,
+            method,
+            args,
+        } => (*canister, method.clone(), args.clone()),
+        _ => {
+            let now = ic_cdk::api::time();
+            state.status = change_membership(key, &state.action, now);
+            state.executed_at = Some(now);
+            ACTION_MAP.with(|a| a.borrow_mut().insert(key, state));
+            return;
+        }
+    };
+    state.status = ActionStatus::Executing;
+    ACTION_MAP.with(|a| a.borrow_mut().insert(key, state));
+    ic_cdk::spawn(async move {
+        let status = call_canister(canister, method, args).await;
+        ACTION_MAP.with(|a| {
+            let mut map = a.borrow_mut();
+            if let Some(mut state) = map.get(&key) {
+                state.status = status;
+                state.executed_at = Some(ic_cdk::api::time());
+                map.insert(key, state);
+            }
+        });
+    });
+}
+
+async fn call_canister(canister: Principal, method: String, args: Vec<u8>) -> ActionStatus {
+    match ic_cdk::api::call::call_raw(canister, &method, args, 0).await {
+        Ok(mut reply) => {
+            let truncated = reply.len() > MAX_ACTION_REPLY_SIZE;
+            reply.truncate(MAX_ACTION_REPLY_SIZE);
+            ActionStatus::Succeeded { reply, truncated }
+        }
+        Err((code, mut message)) => {
+            truncate_message(&mut message);
+            ActionStatus::Failed {
+                code: Some(code as i32),
+                message,
+            }
+        }
+    }
+}
+
+//the target canister writes the reject message, so the cut can't split a character
+fn truncate_message(message: &mut String) {
+    let mut end = MAX_ACTION_REPLY_SIZE.min(message.len());
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    message.truncate(end);
+}
+
+//a deadline that already passed (for example during an upgrade) fires right away
+fn arm_deadline_timer(key: u64, deadline: u64) {
+    let delay = Duration::from_nanos(deadline.saturating_sub(ic_cdk::api::time()));
+    let id = ic_cdk_timers::set_timer(delay, move || close_at_deadline(key));
+    if let Some(old) = DEADLINE_TIMERS.with(|t| t.borrow_mut().insert(key, id)) {
+        ic_cdk_timers::clear_timer(old);
+    }
+}
+
+fn cancel_deadline_timer(key: u64) {
+    if let Some(old) = DEADLINE_TIMERS.with(|t| t.borrow_mut().remove(&key)) {
+        ic_cdk_timers::clear_timer(old);
+    }
+}
+
+fn weight_of(principal: &Principal) -> u32 {
+    WEIGHT_MAP.with(|w| w.borrow().get(principal).unwrap_or(DEFAULT_WEIGHT))
+}
+
+//token weighted proposals use the snapshot, members who joined after the creation get 0
+fn vote_weight(key: u64, proposal: &Proposal, voter: &Principal) -> u64 {
+    if proposal.ledger.is_some() {
+        BALANCE_MAP.with(|b| b.borrow().get(&(key, *voter)).unwrap_or(0))
+    } else {
+        weight_of(voter) as u64
+    }
+}
+
+//where the balances of a token-weighted proposal come from, the tests use a mock ledger
+trait BalanceSource {
+    async fn balance_of(&self, owner: Principal) -> Result<Nat, VoteError>;
+}
+
+//the ICRC-1 ledger set with set_ledger
+struct Icrc1Ledger(Principal);
+
+impl BalanceSource for Icrc1Ledger {
+    async fn balance_of(&self, owner: Principal) -> Result<Nat, VoteError> {
+        let account = Account {
+            owner,
+            subaccount: None,
+        };
+        let (balance,): (Nat,) = ic_cdk::call(self.0, "icrc1_balance_of", (account,))
+            .await
+            .map_err(|_| VoteError::LedgerUnavailable)?;
+        Ok(balance)
+    }
+}
+
+//reads the balance of every member, one call at a time
+//a balance that doesn't fit in a u64 counts as u64::MAX, the tally saturates anyway
+async fn snapshot_balances(
+    source: &impl BalanceSource,
+    members: Vec<Principal>,
+) -> Result<Vec<(Principal, u64)>, VoteError> {
+    let mut balances = Vec::with_capacity(members.len());
+    for member in members {
+        let balance = source.balance_of(member).await?;
+        balances.push((member, u64::try_from(balance.0).unwrap_or(u64::MAX)));
+    }
+    Ok(balances)
+}
+
+//principals without a stored balance vote with weight 0
+fn store_balances(key: u64, balances: Vec<(Principal, u64)>) {
+    BALANCE_MAP.with(|b| {
+        let mut map = b.borrow_mut();
+        for (member, balance) in balances {
+            map.insert((key, member), balance);
+        }
+    });
+}
+
+fn decide(rule: &VotingRule, tally: &Tally) -> ProposalOutcome {
+    let total = tally
+        .approve
+        .saturating_add(tally.reject)
+        .saturating_add(tally.pass);
+    if total < rule.quorum {
+        return ProposalOutcome::NoQuorum;
+    }
+    //u128 so big weights can't overflow the multiplication
+    let approve = tally.approve as u128 * 100;
+    let needed = (tally.approve as u128 + tally.reject as u128) * rule.approval_percentage as u128;
+    if tally.approve > 0 && approve > needed {
+        ProposalOutcome::Accepted
+    } else {
+        ProposalOutcome::Rejected
+    }
+}
+
+fn is_controller() -> Result<(), VoteError> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err(VoteError::AccessRejected)
+    }
 }
 
 #[ic_cdk::query]
@@ -74,45 +1336,255 @@ fn get_proposal_count() -> u64 {
     PROPOSAL_MAP.with(|p| p.borrow().len())
 }
 
+//proposals in id order, a page stops after limit matches (MAX_PAGE_SIZE at most)
+#[ic_cdk::query]
+fn list_proposals(filter: ProposalFilter) -> ProposalPage {
+    let limit = filter
+        .limit
+        .map_or(MAX_PAGE_SIZE, |l| (l as usize).min(MAX_PAGE_SIZE));
+    let text = filter.text.map(|t| t.to_lowercase());
+    //nothing comes after the last possible id
+    let Some(start) = filter.start_after.map_or(Some(0), |key| key.checked_add(1)) else {
+        return ProposalPage {
+            proposals: vec![],
+            next: None,
+        };
+    };
+    PROPOSAL_MAP.with(|p| {
+        let map = p.borrow();
+        let mut matches = map.range(start..).filter(|(_, proposal)| {
+            filter.status.is_none_or(|s| s == proposal.status())
+                && filter.owner.is_none_or(|o| o == proposal.owner)
+                && text
+                    .as_ref()
+                    .is_none_or(|t| proposal.description.to_lowercase().contains(t))
+        });
+        let proposals: Vec<(u64, Proposal)> = matches.by_ref().take(limit).collect();
+        //only point to a next page if there really is one more match
+        let next = match (proposals.last(), matches.next()) {
+            (Some((last, _)), Some(_)) => Some(*last),
+            _ => None,
+        };
+        ProposalPage { proposals, next }
+    })
+}
+
+#[ic_cdk::query]
+fn get_voting_weight(principal: Principal) -> u32 {
+    weight_of(&principal)
+}
+
+//a weight of 0 takes the right to vote away, remove_voting_weight goes back to DEFAULT_WEIGHT
+#[ic_cdk::update]
+fn set_voting_weight(principal: Principal, weight: u32) -> Result<(), VoteError> {
+    is_controller()?;
+    WEIGHT_MAP.with(|w| w.borrow_mut().insert(principal, weight));
+    Ok(())
+}
+
+//the ICRC-1 ledger used by token weighted proposals, created proposals keep the one they started with
 #[ic_cdk::update]
-fn create_proposal(key: u64, proposal: CreateProposal) -> Option<Proposal> {
+fn set_ledger(ledger: Option<Principal>) -> Result<(), VoteError> {
+    is_controller()?;
+    LEDGER
+        .with(|l| l.borrow_mut().set(LedgerConfig { ledger }))
+        .map_err(|_| VoteError::UpdateError)?;
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_ledger() -> Option<Principal> {
+    LEDGER.with(|l| l.borrow().get().ledger)
+}
+
+//the balance a member had when a token weighted proposal was created
+#[ic_cdk::query]
+fn get_snapshot_balance(key: u64, principal: Principal) -> Option<u64> {
+    BALANCE_MAP.with(|b| b.borrow().get(&(key, principal)))
+}
+
+#[ic_cdk::update]
+fn remove_voting_weight(principal: Principal) -> Result<(), VoteError> {
+    is_controller()?;
+    WEIGHT_MAP.with(|w| w.borrow_mut().remove(&principal));
+    Ok(())
+}
+
+//the size is checked on the whole proposal because the options and the description share MAX_VALUE_SIZE
+fn check_proposal_size(proposal: &Proposal) -> Result<(), VoteError> {
+    let size = proposal.to_bytes().len() as u32;
+    if size > MAX_VALUE_SIZE {
+        return Err(VoteError::ProposalTooLarge {
+            size,
+            max: MAX_VALUE_SIZE,
+        });
+    }
+    Ok(())
+}
+
+//token weighted proposals wait for the ledger before they get an id
+#[ic_cdk::update]
+async fn create_proposal(proposal: CreateProposal) -> Result<u64, VoteError> {
+    check_member()?;
+    let rule = proposal.rule.unwrap_or(DEFAULT_RULE);
+    if rule.approval_percentage >= 100 {
+        return Err(VoteError::InvalidVotingRule);
+    }
+    if proposal.deadline <= ic_cdk::api::time() {
+        return Err(VoteError::InvalidDeadline);
+    }
+    validate_topic(&proposal.topic)?;
+    if let Some(action) = &proposal.action {
+        validate_action(action)?;
+    }
+    let options = if proposal.options.is_empty() {
+        None
+    } else {
+        validate_options(&proposal.options, &proposal.action)?;
+        Some(proposal.options)
+    };
+    if let Some(reveal_deadline) = proposal.reveal_deadline {
+        if reveal_deadline <= proposal.deadline || options.is_some() {
+            return Err(VoteError::InvalidRevealDeadline);
+        }
+    }
+    let ledger = if proposal.token_weighted.unwrap_or(false) {
+        Some(
+            LEDGER
+                .with(|l| l.borrow().get().ledger)
+                .ok_or(VoteError::NoLedger)?,
+        )
+    } else {
+        None
+    };
     let value: Proposal = Proposal {
         description: proposal.description,
         approve: 0u32,
         reject: 0u32,
         pass: 0u32,
         is_active: proposal.is_active,
-        voted: vec![],
+        voted: None,
         owner: ic_cdk::caller(),
+        rule: Some(rule),
+        tally: Some(Tally::default()),
+        outcome: None,
+        deadline: Some(proposal.deadline),
+        ended_at: None,
+        topic: proposal.topic,
+        mode: options
+            .as_ref()
+            .map(|_| proposal.mode.unwrap_or(VotingMode::SingleChoice)),
+        options,
+        reveal_deadline: proposal.reveal_deadline,
+        abstained: None,
+        ledger,
+    };
+    //checked before the ledger is called, nothing is stored yet if it doesn't fit
+    check_proposal_size(&value)?;
+    let balances = match ledger {
+        Some(ledger) => {
+            let members = MEMBER_MAP.with(|m| m.borrow().iter().map(|(k, _)| k).collect());
+            snapshot_balances(&Icrc1Ledger(ledger), members).await?
+        }
+        None => Vec::new(),
     };
+    //the deadline may have passed while we waited for the ledger
+    if proposal.deadline <= ic_cdk::api::time() {
+        return Err(VoteError::InvalidDeadline);
+    }
+    let key = next_proposal_id()?;
+    store_balances(key, balances);
+    if proposal.is_active {
+        arm_deadline_timer(key, proposal.reveal_deadline.unwrap_or(proposal.deadline));
+    } else {
+        cancel_deadline_timer(key);
+    }
+    ACTION_MAP.with(|a| match proposal.action {
+        Some(action) => a.borrow_mut().insert(
+            key,
+            ProposalActionState {
+                action,
+                status: ActionStatus::Pending,
+                executed_at: None,
+            },
+        ),
+        None => a.borrow_mut().remove(&key),
+    });
     //borrow_mut because we want to alter the data
-    PROPOSAL_MAP.with(|p| p.borrow_mut().insert(key, value))
+    add_revision(
+        key,
+        Revision {
+            description: value.description.clone(),
+            deadline: value.deadline,
+            at: Some(ic_cdk::api::time()),
+            author: value.owner,
+        },
+    );
+    PROPOSAL_MAP.with(|p| p.borrow_mut().insert(key, value));
+    Ok(key)
 }
 
+//the owner can change the text and the deadline until the first vote, every version is kept in REVISION_MAP
+//ending goes through end_proposal and an ended proposal can only come back with reopen_proposal
 #[ic_cdk::update]
-fn edit_proposal(key: u64, proposal: CreateProposal) -> Result<(), VoteError> {
+fn edit_proposal(key: u64, proposal: EditProposal) -> Result<(), VoteError> {
     //Save the proposal with map.with
     PROPOSAL_MAP.with(|p| {
         //retrieve a proposal with a given key
         let old_proposal_opt = p.borrow().get(&key);
 
-        let old_proposal = match old_proposal_opt {
+        let mut value = match old_proposal_opt {
             Some(value) => value,
             None => return Err(VoteError::NoSuchProposal),
         };
         //check the caller is the owner
-        if ic_cdk::caller() != old_proposal.owner {
+        let caller = ic_cdk::caller();
+        if caller != value.owner {
             return Err(VoteError::AccessRejected);
         }
-        let value = Proposal {
-            description: proposal.description,
-            approve: old_proposal.approve,
-            reject: old_proposal.reject,
-            pass: old_proposal.pass,
-            is_active: proposal.is_active,
-            voted: old_proposal.voted,
-            owner: ic_cdk::caller(),
+        if value.has_ended() || (value.is_active && !proposal.is_active) {
+            return Err(VoteError::ProposalIsNotActive);
+        }
+        if has_votes(key) {
+            return Err(VoteError::HasVotes);
+        }
+        if proposal.is_active && proposal.deadline <= ic_cdk::api::time() {
+            return Err(VoteError::InvalidDeadline);
+        }
+        if value
+            .reveal_deadline
+            .is_some_and(|reveal_deadline| reveal_deadline <= proposal.deadline)
+        {
+            return Err(VoteError::InvalidRevealDeadline);
+        }
+        let first = Revision {
+            description: value.description.clone(),
+            deadline: value.deadline,
+            at: None,
+            author: value.owner,
         };
+        //the rule, the topic and the action can't change once the proposal exists
+        value.description = proposal.description;
+        value.is_active = proposal.is_active;
+        value.deadline = Some(proposal.deadline);
+        //a revision is smaller than the proposal, so it fits too
+        check_proposal_size(&value)?;
+        //proposals created before revisions were kept get their current text as revision 0 first
+        if REVISION_MAP.with(|r| !r.borrow().contains_key(&(key, 0))) {
+            add_revision(key, first);
+        }
+        add_revision(
+            key,
+            Revision {
+                description: value.description.clone(),
+                deadline: value.deadline,
+                at: Some(ic_cdk::api::time()),
+                author: caller,
+            },
+        );
+        if let (true, Some(end_time)) = (proposal.is_active, value.end_time()) {
+            arm_deadline_timer(key, end_time);
+        }
         let res = p.borrow_mut().insert(key, value);
 
         match res {
@@ -122,6 +1594,160 @@ fn edit_proposal(key: u64, proposal: CreateProposal) -> Result<(), VoteError> {
     })
 }
 
+//opens an ended proposal again until the new deadline, the votes already cast stay
+//this is the only way back for an ended proposal, each reopening is kept with its reason and the outcome it replaced
+#[ic_cdk::update]
+fn reopen_proposal(key: u64, deadline: u64, reason: String) -> Result<(), VoteError> {
+    let mut proposal = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .ok_or(VoteError::NoSuchProposal)?;
+    let caller = ic_cdk::caller();
+    if caller != proposal.owner {
+        return Err(VoteError::AccessRejected);
+    }
+    if proposal.is_active || !proposal.has_ended() {
+        return Err(VoteError::ProposalNotEnded);
+    }
+    if proposal.reveal_deadline.is_some() {
+        return Err(VoteError::SecretBallot);
+    }
+    if reason.trim().is_empty() || reason.len() > MAX_REASON_LENGTH {
+        return Err(VoteError::InvalidReason);
+    }
+    if deadline <= ic_cdk::api::time() {
+        return Err(VoteError::InvalidDeadline);
+    }
+    let action = ACTION_MAP.with(|a| a.borrow().get(&key));
+    if let Some(state) = &action {
+        if !matches!(state.status, ActionStatus::Pending | ActionStatus::Skipped) {
+            return Err(VoteError::ActionAlreadyExecuted);
+        }
+    }
+    let reopening = Reopening {
+        by: caller,
+        at: ic_cdk::api::time(),
+        reason,
+        previous_outcome: proposal.outcome,
+        previous_ended_at: proposal.ended_at,
+    };
+    REOPEN_MAP.with(|r| {
+        let mut map = r.borrow_mut();
+        let next = entries_of(&map, key).len() as u32;
+        map.insert((key, next), reopening);
+    });
+    if let Some(mut state) = action {
+        state.status = ActionStatus::Pending;
+        ACTION_MAP.with(|a| a.borrow_mut().insert(key, state));
+    }
+    RESULTS_MAP.with(|r| r.borrow_mut().remove(&key));
+    //the stored tally has the delegated weight of the last close in it, it is counted again at the next close
+    if proposal.tally.is_some() {
+        proposal.tally = Some(direct_tally(key, &proposal));
+    }
+    proposal.is_active = true;
+    proposal.outcome = None;
+    proposal.ended_at = None;
+    proposal.deadline = Some(deadline);
+    arm_deadline_timer(key, deadline);
+    PROPOSAL_MAP.with(|p| p.borrow_mut().insert(key, proposal));
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn get_proposal_history(key: u64) -> Vec<Revision> {
+    REVISION_MAP.with(|r| entries_of(&r.borrow(), key))
+}
+
+#[ic_cdk::query]
+fn get_reopenings(key: u64) -> Vec<Reopening> {
+    REOPEN_MAP.with(|r| entries_of(&r.borrow(), key))
+}
+
+//members can comment on any proposal, ended ones included
+#[ic_cdk::update]
+fn add_comment(key: u64, text: String, reply_to: Option<u32>) -> Result<u32, VoteError> {
+    let author = check_member()?;
+    if !PROPOSAL_MAP.with(|p| p.borrow().contains_key(&key)) {
+        return Err(VoteError::NoSuchProposal);
+    }
+    validate_comment(&text)?;
+    if let Some(parent) = reply_to {
+        let parent = COMMENT_MAP
+            .with(|c| c.borrow().get(&(key, parent)))
+            .ok_or(VoteError::NoSuchComment)?;
+        if parent.deleted {
+            return Err(VoteError::CommentDeleted);
+        }
+    }
+    COMMENT_MAP.with(|c| {
+        let mut map = c.borrow_mut();
+        //ids are never reused, a deleted comment stays in the map
+        let id = match map.range((key, 0)..=(key, u32::MAX)).last() {
+            Some(((_, last), _)) => last.checked_add(1).ok_or(VoteError::NoCommentIdLeft)?,
+            None => 0,
+        };
+        let comment = Comment {
+            author,
+            text,
+            created_at: ic_cdk::api::time(),
+            edited_at: None,
+            reply_to,
+            deleted: false,
+        };
+        map.insert((key, id), comment);
+        Ok(id)
+    })
+}
+
+#[ic_cdk::update]
+fn edit_comment(key: u64, id: u32, text: String) -> Result<(), VoteError> {
+    let mut comment = own_comment(key, id)?;
+    validate_comment(&text)?;
+    comment.text = text;
+    comment.edited_at = Some(ic_cdk::api::time());
+    COMMENT_MAP.with(|c| c.borrow_mut().insert((key, id), comment));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn delete_comment(key: u64, id: u32) -> Result<(), VoteError> {
+    let mut comment = own_comment(key, id)?;
+    comment.text = String::new();
+    comment.deleted = true;
+    comment.edited_at = Some(ic_cdk::api::time());
+    COMMENT_MAP.with(|c| c.borrow_mut().insert((key, id), comment));
+    Ok(())
+}
+
+//comments of one thread in id order, a page stops after limit matches (MAX_PAGE_SIZE at most)
+#[ic_cdk::query]
+fn list_comments(key: u64, filter: CommentFilter) -> CommentPage {
+    let limit = filter
+        .limit
+        .map_or(MAX_PAGE_SIZE, |l| (l as usize).min(MAX_PAGE_SIZE));
+    //nothing comes after the last possible id
+    let Some(start) = filter.start_after.map_or(Some(0), |id| id.checked_add(1)) else {
+        return CommentPage {
+            comments: vec![],
+            next: None,
+        };
+    };
+    COMMENT_MAP.with(|c| {
+        let map = c.borrow();
+        let mut matches = map
+            .range((key, start)..=(key, u32::MAX))
+            .map(|((_, id), comment)| (id, comment))
+            .filter(|(_, comment)| comment.reply_to == filter.thread);
+        let comments: Vec<(u32, Comment)> = matches.by_ref().take(limit).collect();
+        //only point to a next page if there really is one more match
+        let next = match (comments.last(), matches.next()) {
+            (Some((last, _)), Some(_)) => Some(*last),
+            _ => None,
+        };
+        CommentPage { comments, next }
+    })
+}
+
 #[ic_cdk::update]
 
 fn end_proposal(key: u64) -> Result<(), VoteError> {
@@ -138,8 +1764,15 @@ fn end_proposal(key: u64) -> Result<(), VoteError> {
         if ic_cdk::caller() != proposal.owner {
             return Err(VoteError::AccessRejected);
         }
-        proposal.is_active = false;
+        //the owner can still end it before the deadline
+        if !proposal.is_active {
+            return Err(VoteError::ProposalIsNotActive);
+        }
+        cancel_deadline_timer(key);
+        proposal.close(key);
+        let outcome = proposal.outcome;
         let res = p.borrow_mut().insert(key, proposal);
+        run_action(key, outcome);
 
         match res {
             Some(_) => Ok(()),
@@ -148,6 +1781,7 @@ fn end_proposal(key: u64) -> Result<(), VoteError> {
     })
 }
 
+//voting again while the proposal is open changes the choice, the old vote is taken out of the counters first
 #[ic_cdk::update]
 fn vote(key: u64, choice: Choice) -> Result<(), VoteError> {
     PROPOSAL_MAP.with(|p| {
@@ -156,26 +1790,1016 @@ fn vote(key: u64, choice: Choice) -> Result<(), VoteError> {
             Some(value) => value,
             None => return Err(VoteError::NoSuchProposal),
         };
-        let caller = ic_cdk::caller();
-        //check if voted to the proposal before
-        if proposal.voted.contains(&caller) {
-            return Err(VoteError::AlreadyVoted);
-            //we cannot vote on in active proposal 
-        } else if proposal.is_active == false {
+        let caller = check_member()?;
+        //we cannot vote on in active proposal
+        if !proposal.is_open() {
             return Err(VoteError::ProposalIsNotActive);
         }
+        if proposal.options.is_some() {
+            return Err(VoteError::InvalidBallot);
+        }
+        if proposal.reveal_deadline.is_some() {
+            return Err(VoteError::SecretBallot);
+        }
+        let weight = vote_weight(key, &proposal, &caller);
+        if weight == 0 {
+            return Err(VoteError::NoVotingPower);
+        }
+        let mut tally = proposal.weighted_tally();
+        //check if voted to the proposal before
+        if let Some(previous) = VOTER_MAP.with(|v| v.borrow().get(&(key, caller))) {
+            //migrated voters have no stored choice, so there is nothing we could take back
+            let Some(old_choice) = previous.choice else {
+                return Err(VoteError::AlreadyVoted);
+            };
+            match old_choice {
+                Choice::Approve => proposal.approve -= 1,
+                Choice::Reject => proposal.reject -= 1,
+                Choice::Pass => proposal.pass -= 1,
+            };
+            tally.remove(old_choice, previous.weight);
+        }
         match choice {
             //If we have choice Approve
             Choice::Approve => proposal.approve += 1,
             Choice::Reject => proposal.reject += 1,
             Choice::Pass => proposal.pass += 1,
         };
-        //push it to the vector
-        proposal.voted.push(caller);
+        tally.add(choice, weight);
+        proposal.tally = Some(tally);
+        let record = VoteRecord {
+            choice: Some(choice),
+            weight,
+            voted_at: Some(ic_cdk::api::time()),
+            ballot: None,
+        };
+        VOTER_MAP.with(|v| v.borrow_mut().insert((key, caller), record));
         let res = p.borrow_mut().insert(key, proposal);
         match res {
             Some(_) => Ok(()),
-            None => return Err(VoteError::UpdateError),
+            None => Err(VoteError::UpdateError),
         }
     })
 }
+
+//vote on a proposal with options, voting again while it is open replaces the ballot
+#[ic_cdk::update]
+fn vote_options(key: u64, ballot: Ballot) -> Result<(), VoteError> {
+    let caller = check_member()?;
+    let proposal = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .ok_or(VoteError::NoSuchProposal)?;
+    if !proposal.is_open() {
+        return Err(VoteError::ProposalIsNotActive);
+    }
+    let (Some(options), Some(mode)) = (&proposal.options, proposal.mode) else {
+        return Err(VoteError::InvalidBallot);
+    };
+    validate_ballot(options.len(), mode, &ballot)?;
+    let weight = vote_weight(key, &proposal, &caller);
+    if weight == 0 {
+        return Err(VoteError::NoVotingPower);
+    }
+    let record = VoteRecord {
+        choice: None,
+        weight,
+        voted_at: Some(ic_cdk::api::time()),
+        ballot: Some(ballot),
+    };
+    VOTER_MAP.with(|v| v.borrow_mut().insert((key, caller), record));
+    Ok(())
+}
+
+//round by round counts of a proposal with options, available once it ended
+#[ic_cdk::query]
+fn get_results(key: u64) -> Option<OptionResults> {
+    RESULTS_MAP.with(|r| r.borrow().get(&key))
+}
+
+//commits a hidden vote on a secret ballot, committing again before the deadline replaces it
+#[ic_cdk::update]
+fn commit_vote(key: u64, hash: Vec<u8>) -> Result<(), VoteError> {
+    let caller = check_member()?;
+    let proposal = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .ok_or(VoteError::NoSuchProposal)?;
+    if proposal.reveal_deadline.is_none() {
+        return Err(VoteError::NotSecretBallot);
+    }
+    if !proposal.is_open() {
+        return Err(VoteError::ProposalIsNotActive);
+    }
+    if hash.len() != 32 {
+        return Err(VoteError::InvalidCommitment);
+    }
+    let weight = vote_weight(key, &proposal, &caller);
+    if weight == 0 {
+        return Err(VoteError::NoVotingPower);
+    }
+    let commitment = Commitment {
+        hash,
+        weight,
+        committed_at: ic_cdk::api::time(),
+        revealed: None,
+    };
+    COMMIT_MAP.with(|c| c.borrow_mut().insert((key, caller), commitment));
+    Ok(())
+}
+
+//reveals a committed vote, the choice is only counted when the reveal phase ends
+#[ic_cdk::update]
+fn reveal_vote(key: u64, choice: Choice, salt: Vec<u8>) -> Result<(), VoteError> {
+    reveal(key, ic_cdk::caller(), choice, &salt, ic_cdk::api::time())
+}
+
+fn reveal(
+    key: u64,
+    voter: Principal,
+    choice: Choice,
+    salt: &[u8],
+    now: u64,
+) -> Result<(), VoteError> {
+    let proposal = PROPOSAL_MAP
+        .with(|p| p.borrow().get(&key))
+        .ok_or(VoteError::NoSuchProposal)?;
+    if proposal.reveal_deadline.is_none() {
+        return Err(VoteError::NotSecretBallot);
+    }
+    if !proposal.in_reveal_phase(now) {
+        return Err(VoteError::NotInRevealPhase);
+    }
+    let mut commitment = COMMIT_MAP
+        .with(|c| c.borrow().get(&(key, voter)))
+        .ok_or(VoteError::NoCommitment)?;
+    if commitment.revealed.is_some() {
+        return Err(VoteError::AlreadyRevealed);
+    }
+    if salt.len() > MAX_SALT_LENGTH || commitment_hash(choice, salt) != commitment.hash {
+        return Err(VoteError::RevealMismatch);
+    }
+    commitment.revealed = Some(choice);
+    COMMIT_MAP.with(|c| c.borrow_mut().insert((key, voter), commitment));
+    Ok(())
+}
+
+//how many votes were committed and revealed so far, the choices stay hidden until the end
+#[ic_cdk::query]
+fn get_secret_ballot_status(key: u64) -> Option<SecretBallotStatus> {
+    let proposal = PROPOSAL_MAP.with(|p| p.borrow().get(&key))?;
+    let reveal_deadline = proposal.reveal_deadline?;
+    let start = (key, Principal::from_slice(&[]));
+    let (commitments, revealed) = COMMIT_MAP.with(|c| {
+        c.borrow()
+            .range(start..)
+            .take_while(|((k, _), _)| *k == key)
+            .fold((0, 0), |(all, revealed), (_, commitment)| {
+                (all + 1, revealed + commitment.revealed.is_some() as u32)
+            })
+    });
+    Some(SecretBallotStatus {
+        commitments,
+        revealed,
+        deadline: proposal.deadline,
+        reveal_deadline,
+    })
+}
+
+//gives the caller's vote to another principal, for one topic or for all of them when topic is None
+//the delegation is refused if following the delegate's own delegations leads back to the caller
+#[ic_cdk::update]
+fn delegate(to: Principal, topic: Option<String>) -> Result<(), VoteError> {
+    validate_topic(&topic)?;
+    let caller = check_member()?;
+    //a global delegation is used for every topic that has no delegation of its own, so all of them are checked
+    let topics: BTreeSet<Option<String>> = match &topic {
+        Some(_) => BTreeSet::from([topic.clone()]),
+        None => DELEGATION_MAP.with(|d| {
+            d.borrow()
+                .iter()
+                .map(|(k, _)| k.topic)
+                .chain(std::iter::once(None))
+                .collect()
+        }),
+    };
+    for checked in &topics {
+        //the caller's delegation for this topic is the one we are adding unless it has its own
+        let overridden = topic.is_none()
+            && checked.is_some()
+            && DELEGATION_MAP.with(|d| {
+                d.borrow().contains_key(&DelegationKey {
+                    delegator: caller,
+                    topic: checked.clone(),
+                })
+            });
+        if !overridden && chain_reaches(to, caller, checked) {
+            return Err(VoteError::DelegationCycle);
+        }
+    }
+    DELEGATION_MAP.with(|d| {
+        d.borrow_mut().insert(
+            DelegationKey {
+                delegator: caller,
+                topic,
+            },
+            to,
+        )
+    });
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn undelegate(topic: Option<String>) {
+    let key = DelegationKey {
+        delegator: ic_cdk::caller(),
+        topic,
+    };
+    DELEGATION_MAP.with(|d| d.borrow_mut().remove(&key));
+}
+
+//the delegate used for the topic, falls back to the global delegation like the tally does
+#[ic_cdk::query]
+fn get_delegation(delegator: Principal, topic: Option<String>) -> Option<Principal> {
+    delegate_of(delegator, &topic)
+}
+
+//the action of the proposal and what happened when it ran
+#[ic_cdk::query]
+fn get_action(key: u64) -> Option<ProposalActionState> {
+    ACTION_MAP.with(|a| a.borrow().get(&key))
+}
+
+//the current tally with delegated weight, once the proposal ended this is the final tally
+#[ic_cdk::query]
+fn get_tally(key: u64) -> Option<Tally> {
+    let proposal = PROPOSAL_MAP.with(|p| p.borrow().get(&key))?;
+    //a secret ballot has no tally to show before the reveal phase is over
+    if proposal.is_active && proposal.reveal_deadline.is_some() {
+        return None;
+    }
+    if proposal.is_active {
+        Some(tally_with_delegations(key, &proposal))
+    } else {
+        Some(proposal.weighted_tally())
+    }
+}
+
+#[ic_cdk::query]
+fn get_vote(key: u64, voter: Principal) -> Option<VoteRecord> {
+    VOTER_MAP.with(|v| v.borrow().get(&(key, voter)))
+}
+
+//moves the voted list of old proposals into VOTER_MAP, the proposal is stored again without it
+fn migrate_voters() {
+    let legacy: Vec<(u64, Proposal)> = PROPOSAL_MAP.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, proposal)| proposal.voted.is_some())
+            .collect()
+    });
+    for (key, mut proposal) in legacy {
+        let voters = proposal.voted.take().unwrap_or_default();
+        let record = VoteRecord {
+            choice: None,
+            weight: DEFAULT_WEIGHT as u64,
+            voted_at: None,
+            ballot: None,
+        };
+        VOTER_MAP.with(|v| {
+            let mut map = v.borrow_mut();
+            for voter in voters {
+                map.insert((key, voter), record.clone());
+            }
+        });
+        PROPOSAL_MAP.with(|p| p.borrow_mut().insert(key, proposal));
+    }
+}
+
+#[ic_cdk::query]
+fn get_members() -> Vec<(Principal, Member)> {
+    MEMBER_MAP.with(|m| m.borrow().iter().collect())
+}
+
+#[ic_cdk::query]
+fn is_member_of(principal: Principal) -> bool {
+    is_member(&principal)
+}
+
+//the deployer is the first member, everyone else comes in through an AddMember proposal
+#[ic_cdk::init]
+fn init() {
+    add_member(ic_cdk::caller(), None, ic_cdk::api::time());
+}
+
+//the deadlines are in stable memory but the timers are not, every active proposal gets its timer back
+//canisters deployed before the registry have no members, the principal doing the upgrade becomes the first one
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    if MEMBER_MAP.with(|m| m.borrow().is_empty()) {
+        add_member(ic_cdk::caller(), None, ic_cdk::api::time());
+    }
+    migrate_voters();
+    let pending: Vec<(u64, u64)> = PROPOSAL_MAP.with(|p| {
+        p.borrow()
+            .iter()
+            .filter(|(_, proposal)| proposal.is_active)
+            .filter_map(|(key, proposal)| proposal.end_time().map(|end_time| (key, end_time)))
+            .collect()
+    });
+    for (key, deadline) in pending {
+        arm_deadline_timer(key, deadline);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ended_proposal() -> Proposal {
+        Proposal {
+            description: "test".to_string(),
+            approve: 0,
+            reject: 0,
+            pass: 0,
+            is_active: false,
+            voted: None,
+            owner: Principal::anonymous(),
+            rule: None,
+            tally: None,
+            outcome: None,
+            deadline: None,
+            ended_at: None,
+            topic: None,
+            options: None,
+            mode: None,
+            reveal_deadline: None,
+            abstained: None,
+            ledger: None,
+        }
+    }
+
+    //runs a future that never has to wait, like the ones on the mock ledger
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        let mut context = std::task::Context::from_waker(std::task::Waker::noop());
+        match std::pin::pin!(future).poll(&mut context) {
+            std::task::Poll::Ready(output) => output,
+            std::task::Poll::Pending => panic!("the mock ledger never waits"),
+        }
+    }
+
+    //an ICRC-1 ledger with fixed balances, or one that can't be reached
+    struct MockLedger {
+        balances: BTreeMap<Principal, Nat>,
+        reachable: bool,
+    }
+
+    impl BalanceSource for MockLedger {
+        async fn balance_of(&self, owner: Principal) -> Result<Nat, VoteError> {
+            if !self.reachable {
+                return Err(VoteError::LedgerUnavailable);
+            }
+            Ok(self.balances.get(&owner).cloned().unwrap_or_default())
+        }
+    }
+
+    fn tally(approve: u64, reject: u64, pass: u64) -> Tally {
+        Tally {
+            approve,
+            reject,
+            pass,
+        }
+    }
+
+    //a proposal the way the baseline canister stored it, with the voters inside
+    #[derive(CandidType)]
+    struct LegacyProposal {
+        description: String,
+        approve: u32,
+        reject: u32,
+        pass: u32,
+        is_active: bool,
+        voted: Vec<Principal>,
+        owner: Principal,
+    }
+
+    #[test]
+    fn legacy_voters_move_to_the_voter_map() {
+        let voters = vec![Principal::from_slice(&[1]), Principal::from_slice(&[2])];
+        let legacy = LegacyProposal {
+            description: "old".to_string(),
+            approve: 1,
+            reject: 0,
+            pass: 1,
+            is_active: true,
+            voted: voters.clone(),
+            owner: Principal::from_slice(&[9]),
+        };
+        let proposal = Proposal::from_bytes(Cow::Owned(Encode!(&legacy).unwrap()));
+        assert_eq!(proposal.voted.as_ref(), Some(&voters));
+        PROPOSAL_MAP.with(|p| p.borrow_mut().insert(7, proposal));
+
+        migrate_voters();
+
+        let proposal = PROPOSAL_MAP.with(|p| p.borrow().get(&7)).unwrap();
+        assert!(proposal.voted.is_none());
+        assert_eq!(proposal.description, "old");
+        assert_eq!(
+            (proposal.approve, proposal.reject, proposal.pass),
+            (1, 0, 1)
+        );
+        let tally = proposal.weighted_tally();
+        assert_eq!((tally.approve, tally.reject, tally.pass), (1, 0, 1));
+        for voter in &voters {
+            let record = get_vote(7, *voter).unwrap();
+            assert!(record.choice.is_none());
+            assert_eq!(record.weight, DEFAULT_WEIGHT as u64);
+        }
+        assert_eq!(VOTER_MAP.with(|v| v.borrow().len()), 2);
+        //running it again on the migrated map changes nothing
+        migrate_voters();
+        assert_eq!(VOTER_MAP.with(|v| v.borrow().len()), 2);
+    }
+
+    #[test]
+    fn decide_applies_quorum_and_approval() {
+        let rule = VotingRule {
+            quorum: 3,
+            approval_percentage: 50,
+        };
+        //pass counts for the quorum but not for the approval
+        assert!(matches!(
+            decide(&rule, &tally(1, 0, 1)),
+            ProposalOutcome::NoQuorum
+        ));
+        assert!(matches!(
+            decide(&rule, &tally(1, 0, 2)),
+            ProposalOutcome::Accepted
+        ));
+        //exactly the percentage is not more than it
+        assert!(matches!(
+            decide(&rule, &tally(2, 2, 0)),
+            ProposalOutcome::Rejected
+        ));
+        assert!(matches!(
+            decide(&rule, &tally(3, 2, 0)),
+            ProposalOutcome::Accepted
+        ));
+        //nobody approving is never accepted, even with a 0 percent rule
+        let anything = VotingRule {
+            quorum: 0,
+            approval_percentage: 0,
+        };
+        assert!(matches!(
+            decide(&anything, &tally(0, 0, 5)),
+            ProposalOutcome::Rejected
+        ));
+        //token weights near u64::MAX don't overflow the total or the percentage
+        assert!(matches!(
+            decide(&DEFAULT_RULE, &tally(u64::MAX, u64::MAX - 1, 0)),
+            ProposalOutcome::Accepted
+        ));
+    }
+
+    fn set_delegate(delegator: Principal, topic: Option<&str>, delegate: Principal) {
+        let key = DelegationKey {
+            delegator,
+            topic: topic.map(str::to_string),
+        };
+        DELEGATION_MAP.with(|d| d.borrow_mut().insert(key, delegate));
+    }
+
+    #[test]
+    fn chains_follow_topic_delegations_first() {
+        let [a, b, c, d, e] = [1, 2, 3, 4, 5].map(|i| Principal::from_slice(&[i]));
+        set_delegate(a, None, b);
+        set_delegate(b, None, c);
+        set_delegate(b, Some("budget"), d);
+        assert!(chain_reaches(a, c, &None));
+        assert!(!chain_reaches(a, d, &None));
+        //b has its own delegate for the topic, a's global delegation still leads to b
+        assert!(chain_reaches(a, d, &Some("budget".to_string())));
+        assert!(!chain_reaches(a, c, &Some("budget".to_string())));
+        //a topic without delegations falls back to the global ones
+        assert!(chain_reaches(a, c, &Some("other".to_string())));
+        //delegate refuses cycles, but the walk still stops if one is stored
+        set_delegate(c, None, a);
+        assert!(!chain_reaches(a, e, &None));
+    }
+
+    #[test]
+    fn reject_messages_are_cut_between_characters() {
+        //the 3 byte character would end after MAX_ACTION_REPLY_SIZE
+        let mut message = "a".repeat(MAX_ACTION_REPLY_SIZE - 1) + "€";
+        truncate_message(&mut message);
+        assert_eq!(message.len(), MAX_ACTION_REPLY_SIZE - 1);
+        let mut short = "€".to_string();
+        truncate_message(&mut short);
+        assert_eq!(short, "€");
+    }
+
+    fn succeeded(status: &ActionStatus) -> bool {
+        matches!(status, ActionStatus::Succeeded { .. })
+    }
+
+    #[test]
+    fn members_change_only_through_valid_actions() {
+        let (a, b) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        add_member(a, None, 0);
+        //only members are let in
+        assert!(matches!(require_member(a), Ok(p) if p == a));
+        assert!(matches!(require_member(b), Err(VoteError::AccessRejected)));
+        //a proposal can't add a member twice or remove someone who is not one
+        let add = |member| ProposalAction::AddMember { member };
+        let remove = |member| ProposalAction::RemoveMember { member };
+        assert!(matches!(
+            validate_action(&add(a)),
+            Err(VoteError::InvalidAction)
+        ));
+        assert!(validate_action(&add(b)).is_ok());
+        assert!(matches!(
+            validate_action(&remove(b)),
+            Err(VoteError::InvalidAction)
+        ));
+        assert!(validate_action(&remove(a)).is_ok());
+        //the last member stays, otherwise nobody could propose again
+        assert!(!succeeded(&change_membership(1, &remove(a), 5)));
+        assert!(is_member(&a));
+
+        assert!(succeeded(&change_membership(2, &add(b), 5)));
+        let member = MEMBER_MAP.with(|m| m.borrow().get(&b)).unwrap();
+        assert_eq!((member.proposal, member.added_at), (Some(2), 5));
+        assert!(matches!(require_member(b), Ok(p) if p == b));
+        //membership is checked again when the proposal ends
+        assert!(!succeeded(&change_membership(3, &add(b), 6)));
+        assert!(succeeded(&change_membership(4, &remove(a), 6)));
+        assert!(matches!(require_member(a), Err(VoteError::AccessRejected)));
+        assert!(!succeeded(&change_membership(5, &remove(a), 7)));
+        let call = ProposalAction::CallCanister {
+            canister: a,
+            method: "echo".to_string(),
+            args: vec![],
+        };
+        assert!(!succeeded(&change_membership(6, &call, 7)));
+    }
+
+    fn ranked(ballots: &[(&[u32], u64)]) -> Vec<(Ballot, u64)> {
+        ballots
+            .iter()
+            .map(|(ranking, weight)| (Ballot::Ranked(ranking.to_vec()), *weight))
+            .collect()
+    }
+
+    fn eliminated(results: &OptionResults) -> Vec<Option<u32>> {
+        results.rounds.iter().map(|r| r.eliminated).collect()
+    }
+
+    #[test]
+    fn unique_max_needs_a_single_leader() {
+        assert_eq!(unique_max(&[1, 3, 2]), Some(1));
+        assert_eq!(unique_max(&[3, 3, 2]), None);
+        assert_eq!(unique_max(&[0, 0]), None);
+        assert_eq!(unique_max(&[]), None);
+    }
+
+    #[test]
+    fn single_choice_and_approval_have_one_round() {
+        let single = [(Ballot::Single(1), 2), (Ballot::Single(0), 1)];
+        let results = count_options(2, VotingMode::SingleChoice, &single);
+        assert_eq!(results.rounds[0].counts, [1, 2]);
+        assert_eq!(results.winner, Some(1));
+        //every pick gets the full weight
+        let approval = [
+            (Ballot::Approval(vec![0, 1]), 2),
+            (Ballot::Approval(vec![1]), 1),
+        ];
+        let results = count_options(2, VotingMode::Approval, &approval);
+        assert_eq!(results.rounds.len(), 1);
+        assert_eq!(results.rounds[0].counts, [2, 3]);
+        assert_eq!(results.winner, Some(1));
+    }
+
+    #[test]
+    fn option_counts_saturate_on_big_weights() {
+        let approval = [
+            (Ballot::Approval(vec![0, 1]), u64::MAX),
+            (Ballot::Approval(vec![0]), u64::MAX),
+        ];
+        let results = count_options(2, VotingMode::Approval, &approval);
+        assert_eq!(results.rounds[0].counts, [u64::MAX, u64::MAX]);
+        assert_eq!(results.winner, None);
+        //the majority check doesn't overflow either
+        let ballots = ranked(&[(&[0], u64::MAX), (&[1], 1), (&[2], 1)]);
+        let results = count_options(3, VotingMode::RankedChoice, &ballots);
+        assert_eq!(results.rounds.len(), 1);
+        assert_eq!(results.winner, Some(0));
+    }
+
+    #[test]
+    fn ranked_choice_majority_in_the_first_round() {
+        let ballots = ranked(&[(&[0, 1], 3), (&[1], 1), (&[2], 1)]);
+        let results = count_options(3, VotingMode::RankedChoice, &ballots);
+        assert_eq!(results.rounds.len(), 1);
+        assert_eq!(results.rounds[0].counts, [3, 1, 1]);
+        assert_eq!(results.winner, Some(0));
+    }
+
+    #[test]
+    fn ranked_choice_eliminates_until_a_majority() {
+        let ballots = ranked(&[(&[0], 4), (&[1], 3), (&[2, 1], 3), (&[3, 0], 1)]);
+        let results = count_options(4, VotingMode::RankedChoice, &ballots);
+        assert_eq!(results.rounds[0].counts, [4, 3, 3, 1]);
+        //3 goes to 0, then 1 and 2 tie for last place and the highest index goes
+        assert_eq!(results.rounds[1].counts, [5, 3, 3, 0]);
+        assert_eq!(results.rounds[2].counts, [5, 6, 0, 0]);
+        assert_eq!(eliminated(&results), [Some(3), Some(2), None]);
+        assert_eq!(results.winner, Some(1));
+    }
+
+    #[test]
+    fn ranked_choice_final_tie_has_no_winner() {
+        let ballots = ranked(&[(&[0], 2), (&[1], 2), (&[2], 1)]);
+        let results = count_options(3, VotingMode::RankedChoice, &ballots);
+        //the last two options are not eliminated, their tie is the result
+        assert_eq!(eliminated(&results), [Some(2), None]);
+        assert_eq!(results.rounds[1].counts, [2, 2, 0]);
+        assert_eq!(results.winner, None);
+
+        assert!(matches!(
+            option_outcome(&DEFAULT_RULE, 5, results.winner),
+            ProposalOutcome::Tie
+        ));
+        let rule = VotingRule {
+            quorum: 6,
+            approval_percentage: 50,
+        };
+        assert!(matches!(
+            option_outcome(&rule, 5, results.winner),
+            ProposalOutcome::NoQuorum
+        ));
+        assert!(matches!(
+            option_outcome(&DEFAULT_RULE, 5, Some(1)),
+            ProposalOutcome::Winner { option: 1 }
+        ));
+    }
+
+    #[test]
+    fn ranked_choice_drops_exhausted_ballots() {
+        //the ballot ranking only 2 is not counted once 2 is out, so 3 of 5 is a majority
+        let ballots = ranked(&[(&[0], 3), (&[1], 2), (&[2], 2)]);
+        let results = count_options(3, VotingMode::RankedChoice, &ballots);
+        assert_eq!(eliminated(&results), [Some(2), None]);
+        assert_eq!(results.rounds[1].counts, [3, 2, 0]);
+        assert_eq!(results.winner, Some(0));
+        //nothing counted at all ends right away
+        let ballots = ranked(&[(&[], 1)]);
+        let results = count_options(3, VotingMode::RankedChoice, &ballots);
+        assert_eq!(results.rounds.len(), 1);
+        assert_eq!(results.winner, None);
+    }
+
+    #[test]
+    fn proposals_must_fit_in_stable_memory() {
+        let options: Vec<String> = (0..MAX_OPTIONS)
+            .map(|_| "o".repeat(MAX_OPTION_LENGTH))
+            .collect();
+        let proposal = Proposal {
+            options: Some(options),
+            mode: Some(VotingMode::Approval),
+            ..ended_proposal()
+        };
+        assert!(check_proposal_size(&proposal).is_ok());
+        let proposal = Proposal {
+            description: "a".repeat(MAX_VALUE_SIZE as usize),
+            ..proposal
+        };
+        let Err(VoteError::ProposalTooLarge { size, max }) = check_proposal_size(&proposal) else {
+            panic!("the proposal should be too large");
+        };
+        assert!(size > max);
+        assert_eq!(max, MAX_VALUE_SIZE);
+    }
+
+    #[test]
+    fn ids_continue_after_the_last_proposal() {
+        assert!(matches!(next_proposal_id(), Ok(0)));
+        assert!(matches!(next_proposal_id(), Ok(1)));
+        //a proposal stored under a key chosen by its owner is skipped over
+        PROPOSAL_MAP.with(|p| p.borrow_mut().insert(10, ended_proposal()));
+        assert!(matches!(next_proposal_id(), Ok(11)));
+        assert!(matches!(next_proposal_id(), Ok(12)));
+        //there is no id after u64::MAX
+        PROPOSAL_MAP.with(|p| p.borrow_mut().insert(u64::MAX, ended_proposal()));
+        assert!(matches!(
+            next_proposal_id(),
+            Err(VoteError::NoProposalIdLeft)
+        ));
+    }
+
+    fn filter(start_after: Option<u64>, limit: Option<u32>) -> ProposalFilter {
+        ProposalFilter {
+            status: None,
+            owner: None,
+            text: None,
+            start_after,
+            limit,
+        }
+    }
+
+    fn keys(page: &ProposalPage) -> Vec<u64> {
+        page.proposals.iter().map(|(key, _)| *key).collect()
+    }
+
+    #[test]
+    fn proposals_are_listed_in_pages() {
+        for key in 0..5 {
+            PROPOSAL_MAP.with(|p| p.borrow_mut().insert(key, ended_proposal()));
+        }
+        let page = list_proposals(filter(None, Some(2)));
+        assert_eq!((keys(&page), page.next), (vec![0, 1], Some(1)));
+        let page = list_proposals(filter(page.next, Some(2)));
+        assert_eq!((keys(&page), page.next), (vec![2, 3], Some(3)));
+        //the last page has no next even when it is full
+        let page = list_proposals(filter(page.next, Some(1)));
+        assert_eq!((keys(&page), page.next), (vec![4], None));
+        let page = list_proposals(filter(Some(4), None));
+        assert_eq!((keys(&page), page.next), (vec![], None));
+        let page = list_proposals(filter(Some(u64::MAX), None));
+        assert_eq!((keys(&page), page.next), (vec![], None));
+        //the limit can't go above MAX_PAGE_SIZE
+        for key in 5..MAX_PAGE_SIZE as u64 + 5 {
+            PROPOSAL_MAP.with(|p| p.borrow_mut().insert(key, ended_proposal()));
+        }
+        let page = list_proposals(filter(None, Some(u32::MAX)));
+        assert_eq!(page.proposals.len(), MAX_PAGE_SIZE);
+        assert_eq!(page.next, Some(MAX_PAGE_SIZE as u64 - 1));
+    }
+
+    fn insert_comment(key: u64, id: u32, reply_to: Option<u32>) {
+        let comment = Comment {
+            author: Principal::anonymous(),
+            text: "comment".to_string(),
+            created_at: 0,
+            edited_at: None,
+            reply_to,
+            deleted: false,
+        };
+        COMMENT_MAP.with(|c| c.borrow_mut().insert((key, id), comment));
+    }
+
+    fn thread(thread: Option<u32>, start_after: Option<u32>, limit: Option<u32>) -> CommentFilter {
+        CommentFilter {
+            thread,
+            start_after,
+            limit,
+        }
+    }
+
+    fn ids(page: &CommentPage) -> Vec<u32> {
+        page.comments.iter().map(|(id, _)| *id).collect()
+    }
+
+    #[test]
+    fn comment_threads_are_listed_in_pages() {
+        for id in 0..4 {
+            insert_comment(1, id, None);
+        }
+        insert_comment(1, 4, Some(0));
+        insert_comment(1, 5, Some(0));
+        insert_comment(1, u32::MAX, None);
+        //comments of other proposals never show up
+        insert_comment(0, 0, None);
+        insert_comment(2, 0, None);
+        let page = list_comments(1, thread(None, None, Some(2)));
+        assert_eq!((ids(&page), page.next), (vec![0, 1], Some(1)));
+        //replies in between are skipped, not counted against the limit
+        let page = list_comments(1, thread(None, page.next, Some(2)));
+        assert_eq!((ids(&page), page.next), (vec![2, 3], Some(3)));
+        let page = list_comments(1, thread(None, page.next, Some(2)));
+        assert_eq!((ids(&page), page.next), (vec![u32::MAX], None));
+        //there is no id after u32::MAX
+        let page = list_comments(1, thread(None, Some(u32::MAX), None));
+        assert_eq!((ids(&page), page.next), (vec![], None));
+        let page = list_comments(1, thread(Some(0), None, None));
+        assert_eq!((ids(&page), page.next), (vec![4, 5], None));
+        let page = list_comments(1, thread(Some(1), None, None));
+        assert_eq!((ids(&page), page.next), (vec![], None));
+        //the limit can't go above MAX_PAGE_SIZE
+        for id in 0..MAX_PAGE_SIZE as u32 + 1 {
+            insert_comment(3, id, None);
+        }
+        let page = list_comments(3, thread(None, None, Some(u32::MAX)));
+        assert_eq!(page.comments.len(), MAX_PAGE_SIZE);
+        assert_eq!(page.next, Some(MAX_PAGE_SIZE as u32 - 1));
+    }
+
+    #[test]
+    fn drafts_are_not_listed_as_ended() {
+        let active = Proposal {
+            is_active: true,
+            tally: Some(Tally::default()),
+            description: "Budget".to_string(),
+            ..ended_proposal()
+        };
+        let draft = Proposal {
+            tally: Some(Tally::default()),
+            ..ended_proposal()
+        };
+        let ended = Proposal {
+            tally: Some(Tally::default()),
+            ended_at: Some(1),
+            ..ended_proposal()
+        };
+        //proposals ended before ended_at existed
+        let legacy = ended_proposal();
+        for (key, proposal) in [active, draft, ended, legacy].into_iter().enumerate() {
+            PROPOSAL_MAP.with(|p| p.borrow_mut().insert(key as u64, proposal));
+        }
+        let listed = |status| {
+            keys(&list_proposals(ProposalFilter {
+                status: Some(status),
+                ..filter(None, None)
+            }))
+        };
+        assert_eq!(listed(ProposalStatus::Active), [0]);
+        assert_eq!(listed(ProposalStatus::Draft), [1]);
+        assert_eq!(listed(ProposalStatus::Ended), [2, 3]);
+        let page = list_proposals(ProposalFilter {
+            text: Some("bUdG".to_string()),
+            ..filter(None, None)
+        });
+        assert_eq!(keys(&page), [0]);
+    }
+
+    #[test]
+    fn reopening_keeps_migrated_voters_in_the_tally() {
+        let key = 7;
+        let record = |choice: Option<Choice>, weight: u64| VoteRecord {
+            choice,
+            weight,
+            voted_at: None,
+            ballot: None,
+        };
+        VOTER_MAP.with(|v| {
+            let mut map = v.borrow_mut();
+            //two migrated voters, one of them approved and one rejected
+            map.insert((key, Principal::from_slice(&[1])), record(None, 1));
+            map.insert((key, Principal::from_slice(&[2])), record(None, 1));
+            map.insert(
+                (key, Principal::from_slice(&[3])),
+                record(Some(Choice::Approve), 5),
+            );
+            //another proposal is not counted
+            map.insert(
+                (key + 1, Principal::from_slice(&[3])),
+                record(Some(Choice::Pass), 9),
+            );
+        });
+        let proposal = Proposal {
+            approve: 2,
+            reject: 1,
+            //has the delegated weight of the last close in it
+            tally: Some(tally(20, 10, 0)),
+            ended_at: Some(1),
+            ..ended_proposal()
+        };
+        let direct = direct_tally(key, &proposal);
+        assert_eq!(
+            (direct.approve, direct.reject, direct.pass),
+            (5 + DEFAULT_WEIGHT as u64, DEFAULT_WEIGHT as u64, 0)
+        );
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn commitments_hash_the_choice_byte_and_the_salt() {
+        //sha256 of the single byte 0
+        assert_eq!(
+            hex(&commitment_hash(Choice::Approve, &[])),
+            "6e340b9cffb37a989ca544e6bb780a2c78901d3fb33738768511a30617afa01d"
+        );
+        //sha256 of 1 followed by "salt"
+        assert_eq!(
+            hex(&commitment_hash(Choice::Reject, b"salt")),
+            "47110f2f0b1419fc39fec10d9cdf7487b458ea80b264aae71fd9bedc2257191a"
+        );
+        assert_ne!(
+            commitment_hash(Choice::Pass, b"salt"),
+            commitment_hash(Choice::Reject, b"salt")
+        );
+    }
+
+    #[test]
+    fn reveals_must_match_the_commitment_in_the_reveal_phase() {
+        let key = 3;
+        let (a, b, c) = (
+            Principal::from_slice(&[1]),
+            Principal::from_slice(&[2]),
+            Principal::from_slice(&[3]),
+        );
+        let secret = Proposal {
+            is_active: true,
+            tally: Some(Tally::default()),
+            deadline: Some(10),
+            reveal_deadline: Some(20),
+            ..ended_proposal()
+        };
+        PROPOSAL_MAP.with(|p| p.borrow_mut().insert(key, secret));
+        let commit = |voter, choice, salt: &[u8], weight| {
+            let commitment = Commitment {
+                hash: commitment_hash(choice, salt),
+                weight,
+                committed_at: 5,
+                revealed: None,
+            };
+            COMMIT_MAP.with(|c| c.borrow_mut().insert((key, voter), commitment));
+        };
+        commit(a, Choice::Approve, b"salt", 3);
+        commit(b, Choice::Reject, b"other", 2);
+
+        //only between the deadline and the reveal deadline
+        assert!(matches!(
+            reveal(key, a, Choice::Approve, b"salt", 9),
+            Err(VoteError::NotInRevealPhase)
+        ));
+        assert!(matches!(
+            reveal(key, a, Choice::Approve, b"salt", 20),
+            Err(VoteError::NotInRevealPhase)
+        ));
+        assert!(matches!(
+            reveal(key, c, Choice::Approve, b"salt", 15),
+            Err(VoteError::NoCommitment)
+        ));
+        for (choice, salt) in [
+            (Choice::Approve, b"pepper".as_slice()),
+            (Choice::Reject, b"salt".as_slice()),
+            (Choice::Approve, &[0; MAX_SALT_LENGTH + 1]),
+        ] {
+            assert!(matches!(
+                reveal(key, a, choice, salt, 15),
+                Err(VoteError::RevealMismatch)
+            ));
+        }
+        assert!(reveal(key, a, Choice::Approve, b"salt", 15).is_ok());
+        assert!(matches!(
+            reveal(key, a, Choice::Approve, b"salt", 16),
+            Err(VoteError::AlreadyRevealed)
+        ));
+        PROPOSAL_MAP.with(|p| p.borrow_mut().insert(key + 1, ended_proposal()));
+        assert!(matches!(
+            reveal(key + 1, a, Choice::Approve, b"salt", 15),
+            Err(VoteError::NotSecretBallot)
+        ));
+
+        //b never revealed and abstains
+        let mut proposal = PROPOSAL_MAP.with(|p| p.borrow().get(&key)).unwrap();
+        publish_reveals(key, &mut proposal);
+        assert_eq!((proposal.approve, proposal.reject), (1, 0));
+        let tally = proposal.weighted_tally();
+        assert_eq!((tally.approve, tally.reject, tally.pass), (3, 0, 0));
+        assert_eq!(proposal.abstained, Some(1));
+        assert!(matches!(
+            get_vote(key, a),
+            Some(VoteRecord {
+                choice: Some(Choice::Approve),
+                weight: 3,
+                ..
+            })
+        ));
+        assert!(get_vote(key, b).is_none());
+    }
+
+    #[test]
+    fn token_weights_come_from_the_balance_snapshot() {
+        let [rich, whale, broke, outsider] = [1, 2, 3, 4].map(|i| Principal::from_slice(&[i]));
+        let mut ledger = MockLedger {
+            balances: BTreeMap::from([(rich, Nat::from(100u64)), (whale, Nat::from(u128::MAX))]),
+            reachable: true,
+        };
+        let key = 3;
+        let Ok(balances) = block_on(snapshot_balances(&ledger, vec![rich, whale, broke])) else {
+            panic!("the mock ledger is reachable");
+        };
+        assert_eq!(balances, [(rich, 100), (whale, u64::MAX), (broke, 0)]);
+        store_balances(key, balances);
+
+        let proposal = Proposal {
+            ledger: Some(Principal::anonymous()),
+            ..ended_proposal()
+        };
+        assert_eq!(vote_weight(key, &proposal, &rich), 100);
+        assert_eq!(vote_weight(key, &proposal, &whale), u64::MAX);
+        assert_eq!(vote_weight(key, &proposal, &broke), 0);
+        //members added after the proposal was created have no snapshot
+        assert_eq!(vote_weight(key, &proposal, &outsider), 0);
+        //balances are per proposal
+        assert_eq!(vote_weight(key + 1, &proposal, &rich), 0);
+
+        ledger.reachable = false;
+        assert!(matches!(
+            block_on(snapshot_balances(&ledger, vec![rich])),
+            Err(VoteError::LedgerUnavailable)
+        ));
+    }
+}
@@ -5,6 +5,7 @@ const ProposalForm = ({ editMode, proposalId, refreshProposals }) => {
  
   const [description, setDescription] = useState('');
   const [isActive, setIsActive] = useState(true);
+  const [deadline, setDeadline] = useState('');
 
   useEffect(() => {
     if (editMode && proposalId !== null) {
@@ -15,6 +16,12 @@ const ProposalForm = ({ editMode, proposalId, refreshProposals }) => {
       
           setDescription(proposal.description);
           setIsActive(proposal.is_active);
+          if (proposal.deadline.length > 0) {
+            // nanoseconds to the value of a datetime-local input, which is in local time
+            const date = new Date(Number(proposal.deadline[0] / 1_000_000n));
+            const local = new Date(date.getTime() - date.getTimezoneOffset() * 60_000);
+            setDeadline(local.toISOString().slice(0, 16));
+          }
         }
       };
       fetchProposal();
@@ -23,17 +30,32 @@ const ProposalForm = ({ editMode, proposalId, refreshProposals }) => {
 
   const handleSubmit = async (e) => {
     e.preventDefault();
-    const proposalData = {  description, is_active: isActive };
+    const deadlineNanos = BigInt(new Date(deadline).getTime()) * 1_000_000n;
     if (editMode) {
-      await proposal2_backend.edit_proposal(proposalId, proposalData);
+      await proposal2_backend.edit_proposal(proposalId, {
+        description,
+        is_active: isActive,
+        deadline: deadlineNanos,
+      });
     } else {
-      const proposalCount = await proposal2_backend.get_proposal_count();
-      await proposal2_backend.create_proposal(proposalCount, proposalData);
+      await proposal2_backend.create_proposal({
+        description,
+        is_active: isActive,
+        rule: [],
+        deadline: deadlineNanos,
+        topic: [],
+        action: [],
+        options: [],
+        mode: [],
+        reveal_deadline: [],
+        token_weighted: [],
+      });
     }
     refreshProposals();
    
     setDescription('');
     setIsActive(true);
+    setDeadline('');
   };
 
   return (
@@ -45,6 +67,15 @@ const ProposalForm = ({ editMode, proposalId, refreshProposals }) => {
         onChange={(e) => setDescription(e.target.value)}
         placeholder="Enter proposal description"
       />
+      <label>
+        Deadline:
+        <input
+          type="datetime-local"
+          value={deadline}
+          onChange={(e) => setDeadline(e.target.value)}
+          required
+        />
+      </label>
       <label>
         Active:
         <input
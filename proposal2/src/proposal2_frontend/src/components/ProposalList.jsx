@@ -3,21 +3,25 @@ import { proposal2_backend } from "../../../declarations/proposal2_backend";
 
 const ProposalList = ({ toggleEditMode, refreshProposals }) => {
   const [proposals, setProposals] = useState([]);
-  const [proposalCount, setProposalCount] = useState(0);
 
   useEffect(() => {
     const fetchProposals = async () => {
-      const count = await proposal2_backend.get_proposal_count();
-      setProposalCount(count);
       const allProposals = [];
-      for (let i = 0; i < count; i++) {
-        const proposalData = await proposal2_backend.get_proposal(i);
-        if (proposalData && proposalData.length > 0) {
-          const proposal = proposalData[0]; // Unwrap the proposal data
-          console.log("Fetched proposal:", proposal);
-          allProposals.push({ id: i, ...proposal });
+      let startAfter = [];
+      // follow the pages until the backend says there is no next one
+      do {
+        const page = await proposal2_backend.list_proposals({
+          status: [],
+          owner: [],
+          text: [],
+          start_after: startAfter,
+          limit: [],
+        });
+        for (const [id, proposal] of page.proposals) {
+          allProposals.push({ id, ...proposal });
         }
-      }
+        startAfter = page.next;
+      } while (startAfter.length > 0);
       setProposals(allProposals);
     };
 
@@ -40,7 +44,6 @@ const ProposalList = ({ toggleEditMode, refreshProposals }) => {
       <ul>
         {proposals.map((proposal) => (
           <li key={proposal.id}>
-            {console.log("Proposal Description:", proposal.description)}
             <h2>{proposal.name}</h2>
             <p>{proposal.description}</p>
             <p>
@@ -57,6 +60,9 @@ const ProposalList = ({ toggleEditMode, refreshProposals }) => {
             <p>Pass: {proposal.pass}       <button onClick={() => handleVote(proposal.id, { Pass: null })}>
               Pass
             </button></p>
+            {proposal.outcome.length > 0 && (
+              <p>Outcome: {Object.keys(proposal.outcome[0])[0]}</p>
+            )}
 
           
       